@@ -0,0 +1,139 @@
+//! Derive macro for the `Trace` trait of `generational-gc`. This crate should be used through the
+//! `derive` feature of `generational-gc` rather than being depended on directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error, Field, Fields, Result, Type};
+
+/// Implement `Trace` by visiting every field of a struct or enum. Fields which do not hold any
+/// garbage collected pointers can be excluded with `#[trace(skip)]`, in which case their type does
+/// not need to implement `Trace`. Every other field must implement `Trace` so a `GcPtr` can not be
+/// hidden from the collector behind a type which does not know how to report it.
+#[proc_macro_derive(Trace, attributes(trace))]
+pub fn derive_trace(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(mut input: DeriveInput) -> Result<TokenStream2> {
+    let mut traced = Vec::new();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, visits) = destructure(&data.fields, &mut traced)?;
+            quote! {
+                let Self #pattern = self;
+                #(#visits)*
+            }
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            for variant in &data.variants {
+                let ident = &variant.ident;
+                let (pattern, visits) = destructure(&variant.fields, &mut traced)?;
+                arms.push(quote! {
+                    Self::#ident #pattern => { #(#visits)* }
+                });
+            }
+
+            quote! {
+                match self {
+                    #(#arms)*
+                    #[allow(unreachable_patterns)]
+                    _ => {}
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span,
+                "Trace can not be derived for unions",
+            ))
+        }
+    };
+
+    for param in input.generics.type_params_mut() {
+        param
+            .bounds
+            .push(parse_quote!(::generational_gc::trace::Trace));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::generational_gc::trace::Trace for #ident #ty_generics #where_clause {
+            const IS_LEAF: bool = true #(&& <#traced as ::generational_gc::trace::TracedField>::IS_LEAF)*;
+
+            #[allow(unused_variables)]
+            unsafe fn trace(&self, cxt: &mut ::generational_gc::trace::TraceContext) {
+                #body
+            }
+        }
+    })
+}
+
+/// Produce a pattern binding every field along with the statements which trace them. The types of
+/// traced fields are added to `traced`.
+fn destructure<'a>(
+    fields: &'a Fields,
+    traced: &mut Vec<&'a Type>,
+) -> Result<(TokenStream2, Vec<TokenStream2>)> {
+    let mut bindings = Vec::new();
+    let mut visits = Vec::new();
+
+    for (idx, field) in fields.iter().enumerate() {
+        let binding = format_ident!("__field_{}", idx);
+
+        if !is_skipped(field)? {
+            // Span the call with the field type so a missing impl is reported on the field
+            let span = field.ty.span();
+            let ty = &field.ty;
+            visits.push(quote_spanned! {span=>
+                <#ty as ::generational_gc::trace::TracedField>::trace_field(#binding, cxt);
+            });
+            traced.push(ty);
+        }
+
+        bindings.push(binding);
+    }
+
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!({ #(#names: #bindings),* })
+        }
+        Fields::Unnamed(_) => quote!(( #(#bindings),* )),
+        Fields::Unit => TokenStream2::new(),
+    };
+
+    Ok((pattern, visits))
+}
+
+/// Check for `#[trace(skip)]` on a field
+fn is_skipped(field: &Field) -> Result<bool> {
+    let mut skip = false;
+
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("trace"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("unknown trace attribute, expected `skip`"))
+            }
+        })?;
+    }
+
+    Ok(skip)
+}
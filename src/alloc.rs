@@ -1,44 +1,1414 @@
-use crate::ref_table::RefTable;
-use std::marker::PhantomData;
+use crate::borrow::{BorrowError, BorrowGuard, BorrowTable, GcRef, GcRefMut};
+use crate::collect::marker::Marker;
+use crate::collect::safepoint::{Safepoint, SafepointStats};
+use crate::collect::{AccessCounter, IncrementGuard, VisitHeap};
+use crate::finalize::{FinalizationQueue, Finalize, FinalizerThread};
+use crate::handle::{HandleScope, Root};
+use crate::ref_table::{RefTable, SlotCache, DEFAULT_BLOCK_SIZE};
+use parking_lot::Mutex;
 use std::sync::Arc;
 
-use crate::mem::block::OwnedMemoryBlock;
-use crate::mem::{Heap, HeapRegion};
-use crate::ptr::GcPtr;
-use crate::trace::{AnnotatedMixedHeap, Trace};
+use crate::mark::MarkWord;
+use crate::mem::card::CardTables;
+use crate::mem::large::{is_large_object, LargeObjectSpace};
+use crate::mem::nursery::{Nursery, TenuringPolicy, Tlab};
+use crate::mem::old::OldGeneration;
+use crate::mem::{Heap, HeapBudget};
+use crate::ptr::{gc_ptr, DirectObjPtr, DirectObjUnknown, GcPtr, Handle};
+use crate::trace::{AnnotatedMixedHeap, HeapObjectLayout, HeapObjectSetup, Trace};
+use crate::weak::WeakRefs;
+use std::alloc::Layout;
 #[cfg(feature = "allocator_api")]
 use std::alloc::{Allocator, Global};
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, ThreadId};
+
+/// The largest size of the regions the old generation grows by
+const OLD_REGION_SIZE: usize = 1 << 20;
+
+/// Builder used to configure the sizes of the different areas of a `VirtualMachine`'s heap.
+#[derive(Debug, Copy, Clone)]
+pub struct VirtualMachineBuilder {
+    nursery_size: usize,
+    tlab_size: usize,
+    survivor_size: Option<usize>,
+    tenuring: TenuringPolicy,
+    old_size: usize,
+    max_heap_size: usize,
+    ref_block_size: usize,
+    large_object_threshold: Option<usize>,
+    soft_ref_lru_ms_per_mb: u64,
+}
+
+impl Default for VirtualMachineBuilder {
+    fn default() -> Self {
+        VirtualMachineBuilder {
+            nursery_size: 8 << 20,
+            tlab_size: 256 << 10,
+            survivor_size: None,
+            tenuring: TenuringPolicy::default(),
+            old_size: 32 << 20,
+            max_heap_size: 256 << 20,
+            ref_block_size: DEFAULT_BLOCK_SIZE,
+            large_object_threshold: None,
+            soft_ref_lru_ms_per_mb: 1000,
+        }
+    }
+}
+
+impl VirtualMachineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The total number of bytes available to the young generation
+    pub fn nursery_size(mut self, bytes: usize) -> Self {
+        self.nursery_size = bytes;
+        self
+    }
+
+    /// The number of bytes handed out to a thread each time it requests a new TLAB
+    pub fn tlab_size(mut self, bytes: usize) -> Self {
+        self.tlab_size = bytes;
+        self
+    }
+
+    /// The size of each of the two survivor spaces within the nursery. The rest of the nursery is
+    /// used for eden. Defaults to an eighth of the nursery size.
+    pub fn survivor_size(mut self, bytes: usize) -> Self {
+        self.survivor_size = Some(bytes);
+        self
+    }
+
+    /// How many minor collections objects must survive before being promoted to the old
+    /// generation. Defaults to an adaptive threshold targeting half of the survivor space.
+    pub fn tenuring(mut self, policy: TenuringPolicy) -> Self {
+        self.tenuring = policy;
+        self
+    }
+
+    /// The number of bytes the old generation is expected to hold. Nothing is reserved up front,
+    /// instead the old generation grows in regions of this size, capped at 1 MiB.
+    pub fn old_size(mut self, bytes: usize) -> Self {
+        self.old_size = bytes;
+        self
+    }
+
+    /// The upper limit on the number of bytes the heap may grow to. Whatever is not used by the
+    /// nursery is shared between the old generation and the large object space.
+    pub fn max_heap_size(mut self, bytes: usize) -> Self {
+        self.max_heap_size = bytes;
+        self
+    }
+
+    /// The number of slots added to the reference table each time it grows
+    pub fn ref_block_size(mut self, slots: usize) -> Self {
+        self.ref_block_size = slots;
+        self
+    }
+
+    /// Objects of at least this many bytes (including their header) are placed in the large object
+    /// space instead of a TLAB. Defaults to half of the TLAB size.
+    pub fn large_object_threshold(mut self, bytes: usize) -> Self {
+        self.large_object_threshold = Some(bytes);
+        self
+    }
+
+    /// How long an object only reachable through soft references may go unused for each megabyte
+    /// of free heap before a major collection clears the references. Defaults to one second.
+    pub fn soft_ref_lru_ms_per_mb(mut self, millis: u64) -> Self {
+        self.soft_ref_lru_ms_per_mb = millis;
+        self
+    }
+
+    /// Create a new virtual machine from this configuration.
+    ///
+    /// # Panics
+    /// Panics if the configuration is inconsistent, such as a TLAB which is larger than eden or a
+    /// nursery and old generation which do not fit within the maximum heap size.
+    pub fn build<T>(self) -> VirtualMachine<T> {
+        let survivor_size = self
+            .survivor_size
+            .unwrap_or(self.nursery_size / 8 / Tlab::heap_align() * Tlab::heap_align());
+
+        assert!(self.tlab_size > 0, "TLAB size must be non-zero");
+        assert!(
+            2 * survivor_size < self.nursery_size,
+            "Survivor spaces must leave room for eden within the nursery"
+        );
+        let eden_size = self.nursery_size - 2 * survivor_size;
+        assert!(
+            self.tlab_size <= eden_size,
+            "TLAB size must not exceed the size of eden"
+        );
+        assert!(
+            self.nursery_size + self.old_size <= self.max_heap_size,
+            "Nursery and old generation must fit within the maximum heap size"
+        );
+
+        let large_object_threshold = self
+            .large_object_threshold
+            .unwrap_or(self.tlab_size / 2)
+            .min(self.tlab_size);
+        let budget = Arc::new(HeapBudget::new(self.max_heap_size - self.nursery_size));
+        let old = OldGeneration::new(
+            budget.clone(),
+            self.old_size.min(OLD_REGION_SIZE).max(self.tlab_size),
+        );
+
+        VirtualMachine {
+            ref_table: Arc::new(RefTable::with_block_size(self.ref_block_size)),
+            nursery: Mutex::new(
+                Nursery::new(eden_size, self.tlab_size, survivor_size).with_tenuring(self.tenuring),
+            ),
+            cards: old.cards().clone(),
+            old: Mutex::new(old),
+            large_objects: Mutex::new(LargeObjectSpace::new(large_object_threshold, budget)),
+            large_object_threshold,
+            soft_ref_lru_ms_per_mb: self.soft_ref_lru_ms_per_mb,
+            threads: Mutex::new(Vec::new()),
+            weak_refs: WeakRefs::default(),
+            finalization: FinalizationQueue::default(),
+            borrows: BorrowTable::default(),
+            safepoint: Safepoint::default(),
+            old_size: self.old_size,
+            max_heap_size: self.max_heap_size,
+            #[cfg(feature = "allocator_api")]
+            allocator: Global,
+        }
+    }
+}
 
 pub struct VirtualMachine<T, #[cfg(feature = "allocator_api")] A: Allocator = Global> {
     ref_table: Arc<RefTable<T>>,
+    nursery: Mutex<Nursery>,
+    old: Mutex<OldGeneration>,
+    /// The card tables of the old generation, which are only changed while the world is stopped
+    cards: Arc<CardTables>,
+    large_objects: Mutex<LargeObjectSpace>,
+    large_object_threshold: usize,
+    soft_ref_lru_ms_per_mb: u64,
+    threads: Mutex<Vec<Arc<ThreadState>>>,
+    weak_refs: WeakRefs,
+    finalization: FinalizationQueue,
+    borrows: BorrowTable,
+    safepoint: Safepoint,
+    old_size: usize,
+    max_heap_size: usize,
     #[cfg(feature = "allocator_api")]
     allocator: A,
 }
 
 impl<T> VirtualMachine<T> {
-    pub fn make_allocator(&self) -> ThreadAllocator<T> {
+    pub fn builder() -> VirtualMachineBuilder {
+        VirtualMachineBuilder::new()
+    }
+
+    /// A builder for the small heaps shared by the tests
+    #[cfg(test)]
+    pub(crate) fn test_builder() -> VirtualMachineBuilder {
+        Self::builder().nursery_size(64 << 10).tlab_size(4 << 10)
+    }
+
+    /// Register the current thread as a mutator. The returned allocator acts as the thread's
+    /// registration, and the thread is detached once it is dropped. The thread starts without a
+    /// TLAB, since a TLAB taken before the thread is registered could not be retired by a
+    /// collection, and requests one on its first allocation.
+    pub fn attach_thread(&self) -> ThreadAllocator<'_, T> {
+        let state = Arc::new(ThreadState {
+            id: thread::current().id(),
+            counter: AccessCounter::default(),
+            native: AtomicBool::new(false),
+            borrows: Cell::new(0),
+            tlab: UnsafeCell::new(None),
+            slots: UnsafeCell::new(SlotCache::default()),
+        });
+        self.threads.lock().push(state.clone());
+
         ThreadAllocator {
-            tlab: todo!(),
+            state,
             ref_table: self.ref_table.clone(),
             lock_record: Vec::new(),
-            _phantom: PhantomData,
+            vm: self,
+            _not_sync: PhantomData,
+        }
+    }
+
+    /// Detach a thread from the VM. Any objects left in its TLAB remain in the nursery.
+    pub fn detach_thread(&self, allocator: ThreadAllocator<'_, T>) {
+        assert!(
+            std::ptr::eq(allocator.vm, self),
+            "allocator was attached to a different VM"
+        );
+        drop(allocator);
+    }
+
+    /// The ids of every thread currently attached to this VM
+    pub fn attached_threads(&self) -> Vec<ThreadId> {
+        self.threads.lock().iter().map(|thread| thread.id).collect()
+    }
+
+    /// The table holding the slot of every object in this VM
+    pub fn ref_table(&self) -> &RefTable<T> {
+        &self.ref_table
+    }
+
+    /// The weak pointers to objects in this VM
+    pub(crate) fn weak_refs(&self) -> &WeakRefs {
+        &self.weak_refs
+    }
+
+    pub(crate) fn finalization(&self) -> &FinalizationQueue {
+        &self.finalization
+    }
+
+    /// The number of unreachable objects waiting for their finalizers to run
+    pub fn pending_finalizers(&self) -> usize {
+        self.finalization.len()
+    }
+
+    /// Stop every thread for the duration of `f`. Threads are stopped once they reach a safepoint,
+    /// and are released once `f` returns.
+    fn with_world_stopped<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&[Arc<ThreadState>]) -> R,
+    {
+        let threads = self.threads.lock();
+        let current = thread::current().id();
+        assert!(
+            !threads
+                .iter()
+                .any(|thread| thread.id == current && thread.borrows.get() > 0),
+            "can not stop the world while this thread is borrowing objects"
+        );
+
+        let _stopped = self
+            .safepoint
+            .synchronize(threads.iter().map(|thread| &thread.counter));
+
+        // Cached slots are not assigned to objects, so they must not be mistaken for live slots
+        for thread in threads.iter() {
+            unsafe { self.ref_table.flush_cache(&mut *thread.slots.get()) };
+        }
+
+        f(&threads)
+    }
+
+    /// The number of slots in the reference table which are assigned to objects. Stops the world
+    /// so slots cached by threads are not counted.
+    pub fn live_slot_count(&self) -> usize {
+        self.with_world_stopped(|_| unsafe { self.ref_table.live_slots().len() })
+    }
+
+    /// Timings for how long it has taken threads to reach a safepoint when stopping the world
+    pub fn safepoint_stats(&self) -> SafepointStats {
+        self.safepoint.stats()
+    }
+
+    /// Stop every thread and mark all objects reachable from the live slots of the reference
+    /// table. Returns the objects which were found to be reachable.
+    pub fn collect(&self) -> Vec<DirectObjUnknown> {
+        self.with_world_stopped(|_| unsafe {
+            let mut marker = Marker::<AnnotatedMixedHeap>::default();
+            marker.mark_from(self.roots());
+            marker.unmark_reachable();
+            marker.into_reachable()
+        })
+    }
+
+    /// Every object referenced by a live slot in the reference table.
+    ///
+    /// # Safety
+    /// All threads must be stopped while the roots are collected.
+    unsafe fn roots(&self) -> impl Iterator<Item = DirectObjUnknown> {
+        self.root_slots().map(|slot| slot.read())
+    }
+
+    /// Every slot in the reference table which is still a root, along with the slots of objects
+    /// waiting for their finalizers to run.
+    ///
+    /// # Safety
+    /// All threads must be stopped while the roots are collected.
+    unsafe fn root_slots(&self) -> impl Iterator<Item = NonNull<DirectObjUnknown>> {
+        let roots = self.ref_table.root_slots().into_iter().map(NonNull::cast);
+        roots.chain(self.finalization.pending_slots())
+    }
+
+    /// Scavenge the nursery, then free the unrooted slots of young objects which did not survive.
+    /// Every surviving object has its slot rewritten, so a young slot left untouched is dead.
+    ///
+    /// # Safety
+    /// All threads must be stopped and must have retired their TLABs.
+    unsafe fn scavenge(&self, nursery: &mut Nursery, old: &mut OldGeneration) {
+        let young = self
+            .ref_table
+            .unrooted_slots()
+            .into_iter()
+            .map(|slot| (slot.cast::<DirectObjUnknown>(), slot.cast().read()))
+            .filter(|(_, object)| nursery.contains(*object))
+            .collect::<HashMap<NonNull<DirectObjUnknown>, DirectObjUnknown>>();
+
+        // Only major collections look for unreachable objects with finalizers or soft references
+        let roots = self.root_slots();
+        let roots = roots
+            .chain(self.finalization.registered_slots())
+            .chain(self.weak_refs.soft_slots());
+        nursery.scavenge(old, roots);
+        self.reclaim_slots(|slot| young.get(&slot.cast()) == Some(&slot.cast().read()));
+    }
+
+    /// Free the unrooted slots whose objects have died and clear the weak pointers to them.
+    ///
+    /// # Safety
+    /// All threads must be stopped, and `is_dead` must only return true for slots whose object can
+    /// no longer be reached.
+    unsafe fn reclaim_slots<F>(&self, is_dead: F)
+    where
+        F: Fn(NonNull<DirectObjPtr<T>>) -> bool,
+    {
+        let dead = self
+            .ref_table
+            .reclaim_slots(is_dead)
+            .into_iter()
+            .map(NonNull::cast)
+            .collect::<HashSet<_>>();
+
+        self.weak_refs.clear(&dead);
+    }
+
+    /// Check if the old generation is guaranteed to have room for every object in the nursery,
+    /// since a scavenge can not be abandoned once objects have started to be promoted.
+    fn can_promote(&self, nursery: &Nursery, old: &OldGeneration) -> bool {
+        old.promotion_headroom(self.large_object_threshold) >= nursery.used()
+    }
+
+    /// Run a minor collection to reclaim space within the nursery. Live objects are copied out of
+    /// eden and the from-space into the to-space or the old generation, and their slots in the
+    /// reference table are updated to point to the copies. If the old generation may not have
+    /// enough space to hold every promoted object, a compacting major collection is run instead.
+    pub fn collect_minor(&self) {
+        let scavenged = self.with_world_stopped(|threads| {
+            let mut nursery = self.nursery.lock();
+            let mut old = self.old.lock();
+            retire_tlabs(threads, &mut nursery);
+
+            if !self.can_promote(&nursery, &old) {
+                return false;
+            }
+
+            unsafe { self.scavenge(&mut nursery, &mut old) };
+            true
+        });
+
+        if !scavenged {
+            self.collect_major(true);
         }
     }
+
+    /// Run a major collection over the whole heap. Every reachable object is marked, including
+    /// those in the nursery, and unmarked objects in the old generation and the large object space
+    /// are swept. Unreachable objects registered for finalization are resurrected and queued
+    /// instead. When `compact` is set, the survivors within the old generation are slid together
+    /// to remove fragmentation. The nursery is evacuated last so promoted objects can use the space
+    /// which was freed. If the old generation could not hold every young object, the nursery is
+    /// not evacuated and stays full.
+    pub fn collect_major(&self, compact: bool) {
+        self.with_world_stopped(|threads| unsafe {
+            let mut nursery = self.nursery.lock();
+            let mut old = self.old.lock();
+            let mut large_objects = self.large_objects.lock();
+            retire_tlabs(threads, &mut nursery);
+
+            let mut marker = Marker::<AnnotatedMixedHeap>::default();
+            marker.mark_from(self.roots());
+            let is_dead = |slot: NonNull<DirectObjUnknown>| {
+                !AnnotatedMixedHeap::mark(slot.read()).is_marked()
+            };
+
+            // Reference processing runs between marking and sweeping. Soft references to recently
+            // used objects are kept first, then unreachable objects with finalizers are kept alive
+            // until their finalizers have run, and every other reference to an unmarked object is
+            // cleared once its slot is reclaimed.
+            let used = nursery.used() + old.used() + large_objects.allocated();
+            let free_mb = (self.max_heap_size.saturating_sub(used) >> 20) as u64;
+            let max_idle = free_mb.saturating_mul(self.soft_ref_lru_ms_per_mb);
+            let soft = self.weak_refs.retained_soft_slots(is_dead, max_idle);
+            marker.mark_from(soft.into_iter().map(|slot| slot.read()));
+
+            let resurrected = self.finalization.enqueue_unreachable(is_dead);
+            marker.mark_from(resurrected.into_iter().map(|slot| slot.read()));
+
+            self.reclaim_slots(|slot| is_dead(slot.cast()));
+            old.sweep();
+            large_objects.sweep();
+
+            // Young objects are copied along with their mark, so they are unmarked before the
+            // nursery is evacuated
+            nursery.survivors().unmark_heap();
+            for tlab in nursery.retired() {
+                tlab.unmark_heap();
+            }
+
+            if compact {
+                // Unrooted objects which survived still need their slots rewritten
+                let slots = self
+                    .ref_table
+                    .live_slots()
+                    .into_iter()
+                    .map(NonNull::cast::<DirectObjUnknown>)
+                    .map(|slot| (slot.read(), slot))
+                    .collect::<HashMap<_, _>>();
+
+                old.compact(&slots);
+                old.remember_references_into(&nursery.address_ranges());
+            }
+
+            // Without the room to promote every young object, the nursery is left where it is and
+            // allocations which need a new TLAB fail instead
+            if self.can_promote(&nursery, &old) {
+                self.scavenge(&mut nursery, &mut old);
+            }
+        })
+    }
+
+    /// The number of bytes the old generation is expected to hold, which sets the size of the
+    /// regions it grows by
+    pub fn old_size(&self) -> usize {
+        self.old_size
+    }
+
+    /// The upper limit on the number of bytes the heap may grow to
+    pub fn max_heap_size(&self) -> usize {
+        self.max_heap_size
+    }
+}
+
+impl<T: Trace + Finalize + Send + 'static> VirtualMachine<T> {
+    /// Spawn a thread which runs finalizers as the collector queues them, attached to this VM. The
+    /// thread keeps the VM alive until the returned handle is dropped.
+    pub fn start_finalizer_thread(self: &Arc<Self>) -> FinalizerThread<T> {
+        FinalizerThread::spawn(self)
+    }
 }
 
+/// The error returned when the heap is unable to provide space for an object.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AllocError {
+    layout: Layout,
+}
+
+impl AllocError {
+    pub fn new(layout: Layout) -> Self {
+        AllocError { layout }
+    }
+
+    /// The layout of the object which could not be allocated
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
+
+impl Display for AllocError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to allocate {} bytes (align {}) on the gc heap",
+            self.layout.size(),
+            self.layout.align()
+        )
+    }
+}
+
+impl Error for AllocError {}
+
+/// Every TLAB becomes part of eden so the objects within them can be evacuated. Threads will
+/// request a new TLAB on their next allocation.
+fn retire_tlabs(threads: &[Arc<ThreadState>], nursery: &mut Nursery) {
+    for thread in threads {
+        // Safety: The collector has closed the counter of every thread
+        if let Some(tlab) = unsafe { (*thread.tlab.get()).take() } {
+            nursery.retire(tlab);
+        }
+    }
+}
+
+/// The parts of a thread which are shared with the collector
+struct ThreadState {
+    id: ThreadId,
+    counter: AccessCounter,
+    /// Set while the thread is blocked in native code and treated as being at a safepoint
+    native: AtomicBool,
+    /// The number of objects currently borrowed by the thread
+    borrows: Cell<usize>,
+    tlab: UnsafeCell<Option<Tlab>>,
+    slots: UnsafeCell<SlotCache>,
+}
+
+/// The TLAB and slot cache are only accessed by their owning thread while it holds the counter, or
+/// by the collector once the counter has been closed. The borrow count is only used by the owning
+/// thread.
+unsafe impl Send for ThreadState {}
+unsafe impl Sync for ThreadState {}
+
 /// An Allocator<'heap, T> lives for the duration of the heap and acts as a reference to the TLAB
 pub struct ThreadAllocator<'heap, T> {
-    tlab: UnsafeCell<HeapRegion<OwnedMemoryBlock, AnnotatedMixedHeap>>,
+    state: Arc<ThreadState>,
     ref_table: Arc<RefTable<T>>,
     lock_record: Vec<usize>,
-    _phantom: PhantomData<&'heap mut T>,
+    vm: &'heap VirtualMachine<T>,
+    _not_sync: PhantomData<Cell<()>>,
 }
 
 impl<'heap, T: Trace> ThreadAllocator<'heap, T> {
-    pub fn allocate<A>(&self, value: T) -> Result<GcPtr<T>, T> {
-        let direct = unsafe { (&mut *self.tlab.get()).try_push_to_heap(value)? };
-        let indirect = self.ref_table.claim_slot().assign(direct);
-        todo!()
+    /// Allocate a new object in this thread's TLAB and claim a slot in the reference table for it.
+    pub fn allocate(&self, value: T) -> Result<GcPtr<T>, AllocError> {
+        if self.allocates_large_objects() {
+            return self.allocate_large(value);
+        }
+
+        // The object must not be moved or collected until it has been given a slot, so the guard
+        // is held until the slot has been claimed.
+        let value = {
+            let _guard = self.enter();
+            match self.push_to_tlab(value) {
+                Ok(direct) => return Ok(self.claim_slot(direct)),
+                Err(value) => value,
+            }
+        };
+
+        self.allocate_slow(value)
+    }
+
+    /// Enter this thread's counter, parking first if a collection is in progress. A thread which
+    /// is borrowing objects already holds the counter, so it enters again without parking.
+    fn enter(&self) -> IncrementGuard<'_> {
+        match self.state.borrows.get() {
+            0 => self.state.counter.increment_or_savepoint(),
+            _ => self.state.counter.increment(),
+        }
+    }
+
+    /// Check if objects are placed in the large object space, where they are never moved. Only
+    /// objects in the large object space may be reported through `TraceContext::visit_raw`.
+    pub fn allocates_large_objects(&self) -> bool {
+        let layout = <AnnotatedMixedHeap as HeapObjectSetup<T>>::wrap_layout(Layout::new::<T>());
+        is_large_object(layout, self.vm.large_object_threshold)
+    }
+
+    /// Immutably borrow the contents of an object. The object will not be moved or collected until
+    /// the borrow is dropped, so collections started by other threads wait for it to be released.
+    /// While any borrow is held, allocations on this thread fail instead of running a collection.
+    ///
+    /// Only roots can be borrowed rather than any `GcPtr`, since a pointer which is not a root may
+    /// refer to an object which has already been collected. Other threads may hold immutable
+    /// borrows of the same object at the same time through their own roots, so the object must be
+    /// `Sync`.
+    ///
+    /// # Panics
+    /// Panics if the object is mutably borrowed or belongs to another VM. Use `try_borrow` to
+    /// handle these cases.
+    pub fn borrow(&self, ptr: &Root<'_, T>) -> GcRef<'_, T>
+    where
+        T: Sync,
+    {
+        self.try_borrow(ptr).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Mutably borrow the contents of an object. No other borrows of the object may be held at the
+    /// same time, including by other threads.
+    ///
+    /// # Panics
+    /// Panics if the object is already borrowed or belongs to another VM. Use `try_borrow_mut` to
+    /// handle these cases.
+    pub fn borrow_mut(&self, ptr: &Root<'_, T>) -> GcRefMut<'_, T> {
+        self.try_borrow_mut(ptr)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_borrow(&self, ptr: &Root<'_, T>) -> Result<GcRef<'_, T>, BorrowError>
+    where
+        T: Sync,
+    {
+        let ptr = self.owned_ptr(ptr)?;
+        let guard = self.enter();
+        self.vm.borrows.borrow(ptr.slot())?;
+
+        let borrow = BorrowGuard::new(&self.vm.borrows, ptr.slot(), &self.state.borrows, guard);
+        // Safety: The slot was assigned to the object when it was allocated, and the object can
+        // not move while the counter is held
+        unsafe { Ok(GcRef::new(NonNull::new_unchecked(ptr.direct_ptr()), borrow)) }
+    }
+
+    pub fn try_borrow_mut(&self, ptr: &Root<'_, T>) -> Result<GcRefMut<'_, T>, BorrowError> {
+        let ptr = self.owned_ptr(ptr)?;
+        let guard = self.enter();
+        self.vm.borrows.borrow_mut(ptr.slot())?;
+
+        let borrow = BorrowGuard::new(&self.vm.borrows, ptr.slot(), &self.state.borrows, guard);
+        // Safety: As in try_borrow, and the borrow table guarantees no other borrows exist
+        unsafe {
+            Ok(GcRefMut::new(
+                NonNull::new_unchecked(ptr.direct_ptr()),
+                &self.vm.cards,
+                borrow,
+            ))
+        }
+    }
+
+    /// The pointer behind a root, once the root has been checked to belong to this VM. A root can
+    /// not outlive its scope, so its slot is known to still refer to the object.
+    fn owned_ptr(&self, root: &Root<'_, T>) -> Result<GcPtr<T>, BorrowError> {
+        let ptr = gc_ptr(root);
+
+        match self.vm.ref_table.contains(ptr.slot().cast()) {
+            true => Ok(ptr),
+            false => Err(BorrowError::ForeignVm),
+        }
+    }
+
+    fn push_to_tlab(&self, value: T) -> Result<DirectObjPtr<T>, T> {
+        // Safety: ThreadAllocator is not Sync and the caller holds the counter so no other
+        // references to the TLAB can exist
+        match unsafe { &mut *self.state.tlab.get() } {
+            Some(tlab) => tlab.try_push_to_heap(value),
+            None => Err(value),
+        }
     }
+
+    fn claim_slot(&self, direct: DirectObjPtr<T>) -> GcPtr<T> {
+        // Safety: ThreadAllocator is not Sync and the caller holds the counter, so no other
+        // references to the cache can exist
+        let cache = unsafe { &mut *self.state.slots.get() };
+        let indirect = self.ref_table.claim_cached(cache).assign(direct);
+        unsafe { GcPtr::from_slot(indirect) }
+    }
+
+    /// Retires the current TLAB and requests a new one from the nursery. If the nursery is
+    /// exhausted, a minor collection is performed before trying again.
+    #[cold]
+    fn allocate_slow(&self, value: T) -> Result<GcPtr<T>, AllocError> {
+        let error = AllocError::new(Layout::new::<T>());
+        let layout = <AnnotatedMixedHeap as HeapObjectSetup<T>>::wrap_layout(error.layout());
+
+        // The object would not fit even if we had an empty TLAB
+        if !self.vm.nursery.lock().fits_in_tlab(layout) {
+            return Err(error);
+        }
+
+        if !self.refill_tlab() {
+            // The counter must not be held during a collection or the collector will never be
+            // able to stop this thread. Borrows hold the counter until they are dropped.
+            if self.state.borrows.get() > 0 {
+                return Err(error);
+            }
+            self.vm.collect_minor();
+
+            if !self.refill_tlab() {
+                return Err(error);
+            }
+        }
+
+        let _guard = self.enter();
+        let direct = self.push_to_tlab(value).map_err(|_| error)?;
+        Ok(self.claim_slot(direct))
+    }
+
+    /// Place an object in its own block within the large object space. If the heap is out of
+    /// room, a compacting major collection is performed before trying again, since only major
+    /// collections free large objects or release regions of the old generation.
+    #[cold]
+    fn allocate_large(&self, value: T) -> Result<GcPtr<T>, AllocError> {
+        let error = AllocError::new(Layout::new::<T>());
+
+        let value = match self.push_large(value) {
+            Ok(ptr) => return Ok(ptr),
+            Err(value) => value,
+        };
+
+        // Borrows hold the counter, so a collection could never stop this thread
+        if self.state.borrows.get() > 0 {
+            return Err(error);
+        }
+        self.vm.collect_major(true);
+
+        self.push_large(value).map_err(|_| error)
+    }
+
+    fn push_large(&self, value: T) -> Result<GcPtr<T>, T> {
+        let _guard = self.enter();
+        let direct = self.vm.large_objects.lock().try_push_to_heap(value)?;
+        Ok(self.claim_slot(direct))
+    }
+
+    /// Swap the current TLAB for a new one from the nursery. Returns false if the nursery did not
+    /// have space for another TLAB.
+    fn refill_tlab(&self) -> bool {
+        let _guard = self.enter();
+
+        // Safety: ThreadAllocator is not Sync and we hold the counter so no other references to
+        // the TLAB can exist
+        let tlab = unsafe { &mut *self.state.tlab.get() };
+        let mut nursery = self.vm.nursery.lock();
+
+        if let Some(retired) = tlab.take() {
+            nursery.retire(retired);
+        }
+
+        *tlab = nursery.take_tlab();
+        tlab.is_some()
+    }
+
+    /// Check if a collection is waiting for this thread and park until it completes if so. Long
+    /// running code which does not allocate should call this regularly so it does not hold up
+    /// collections started by other threads.
+    #[inline]
+    pub fn safepoint_poll(&self) {
+        if self.vm.safepoint.is_requested() {
+            drop(self.enter());
+        }
+    }
+
+    /// Open a scope which frees the slots of the objects allocated through it once it ends
+    pub fn handle_scope(&self) -> HandleScope<'_, 'heap, T> {
+        HandleScope::new(self)
+    }
+
+    /// Stop treating slots as roots. They are reclaimed by a later collection once their objects
+    /// can no longer be reached from the heap.
+    ///
+    /// # Safety
+    /// Every slot must be a live slot claimed from this VM's reference table.
+    pub(crate) unsafe fn unroot_slots<I>(&self, slots: I)
+    where
+        I: Iterator<Item = NonNull<DirectObjPtr<T>>>,
+    {
+        let _guard = self.enter();
+        self.ref_table.unroot_slots(slots);
+    }
+
+    /// Add a root to the slot returned by `find`, or return None if it returns None. The counter is
+    /// held while searching, so a slot found by `find` can not be freed before it is rooted.
+    ///
+    /// # Panics
+    /// Panics if the slot does not belong to this VM.
+    pub(crate) fn root_slot<F>(&self, find: F) -> Option<GcPtr<T>>
+    where
+        F: FnOnce() -> Option<NonNull<DirectObjUnknown>>,
+    {
+        let _guard = self.enter();
+        let slot = find()?.cast();
+        assert!(
+            self.vm.ref_table.contains(slot),
+            "slot belongs to another vm"
+        );
+
+        // Safety: The slot belongs to this VM and can not be freed while the counter is held
+        unsafe {
+            self.vm.ref_table.root_slot(slot);
+            Some(GcPtr::from_slot(slot))
+        }
+    }
+
+    /// Mark this thread as blocked outside of the heap, such as in I/O or FFI calls. The thread
+    /// counts as being at a safepoint until the returned guard is dropped, even if it is currently
+    /// holding the counter. The heap must not be accessed while in native code.
+    ///
+    /// # Panics
+    /// Panics if the thread is already in native code or is borrowing objects.
+    pub fn enter_native(&self) -> NativeGuard<'_> {
+        assert_eq!(
+            self.state.borrows.get(),
+            0,
+            "borrowed objects may move while in native code"
+        );
+        assert!(
+            !self.state.native.swap(true, Ordering::SeqCst),
+            "thread is already in native code"
+        );
+
+        NativeGuard {
+            state: &self.state,
+            // Safety: ThreadAllocator is not Sync, so every entry belongs to this thread
+            entries: unsafe { self.state.counter.release_entries() },
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Check if this thread is currently treated as being in native code
+    pub fn in_native(&self) -> bool {
+        self.state.native.load(Ordering::SeqCst)
+    }
+
+    /// Perform a store of a reference to `value` into `field` and run the write barrier for it.
+    /// The store is performed while holding the counter so it can not race with the collector,
+    /// which also keeps the card tables from changing while the barrier looks up the card.
+    pub(crate) fn write_barrier<U: ?Sized, F: FnOnce()>(
+        &self,
+        field: NonNull<()>,
+        value: GcPtr<U>,
+        store: F,
+    ) {
+        let _guard = self.enter();
+        store();
+
+        // Safety: Only live objects may be stored into the heap, since the tracer follows every
+        // stored pointer
+        if let Some(value) = NonNull::new(unsafe { value.direct_ptr() }) {
+            self.vm.cards.write_barrier(
+                field.as_ptr() as usize,
+                value.cast::<()>().as_ptr() as usize,
+            );
+        }
+    }
+
+    /// The virtual machine this allocator belongs to
+    pub fn vm(&self) -> &'heap VirtualMachine<T> {
+        self.vm
+    }
+}
+
+impl<'heap, T: Trace + Finalize> ThreadAllocator<'heap, T> {
+    /// Have the finalizer of an object run once it becomes unreachable. Registering an object which
+    /// is already registered has no effect, so the finalizer only runs once.
+    pub fn register_finalizer<H: Handle<T>>(&self, ptr: H) {
+        let _guard = self.enter();
+        self.vm.finalization.register(gc_ptr(&ptr).slot());
+    }
+
+    /// Run the finalizers of every queued object on this thread. Returns the number of finalizers
+    /// which were run. Each finalizer runs while its object is mutably borrowed, so an object
+    /// which is still borrowed elsewhere stays at the front of the queue until a later call.
+    pub fn run_finalizers(&self) -> usize {
+        let mut count = 0;
+
+        loop {
+            // The counter is held before the object leaves the queue so it can not be collected
+            // or moved until its finalizer has finished
+            let guard = self.enter();
+            let slot = match self.vm.finalization.pop() {
+                Some(slot) => slot,
+                None => return count,
+            };
+
+            if self.vm.borrows.borrow_mut(slot).is_err() {
+                self.vm.finalization.requeue(slot);
+                return count;
+            }
+
+            // Allocations made by the finalizer fail as they would while borrowing, since the
+            // counter is held and a collection could never stop this thread
+            let borrow = BorrowGuard::new(&self.vm.borrows, slot, &self.state.borrows, guard);
+            // Safety: Queued slots are roots, so the slot still refers to the object
+            let mut object = unsafe {
+                let ptr = GcPtr::<T>::from_slot(slot.cast());
+                GcRefMut::new(
+                    NonNull::new_unchecked(ptr.direct_ptr()),
+                    &self.vm.cards,
+                    borrow,
+                )
+            };
+
+            object.finalize();
+            count += 1;
+        }
+    }
+}
+
+/// Keeps a thread in native code. Dropping the guard returns the thread to running in the heap,
+/// parking first if a collection is in progress.
+pub struct NativeGuard<'a> {
+    state: &'a ThreadState,
+    entries: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a> NativeGuard<'a> {
+    /// Leave native code. Equivalent to dropping the guard.
+    pub fn exit_native(self) {}
+}
+
+impl<'a> Drop for NativeGuard<'a> {
+    fn drop(&mut self) {
+        // Safety: The entries were released by this thread in enter_native
+        unsafe { self.state.counter.reenter(self.entries) };
+        self.state.native.store(false, Ordering::SeqCst);
+    }
+}
+
+impl<'heap, T> Drop for ThreadAllocator<'heap, T> {
+    fn drop(&mut self) {
+        {
+            let _guard = self.state.counter.increment_or_savepoint();
+
+            // Safety: We hold the counter so the collector can not be accessing the TLAB or slot cache
+            if let Some(tlab) = unsafe { (*self.state.tlab.get()).take() } {
+                self.vm.nursery.lock().retire(tlab);
+            }
+            unsafe { self.ref_table.flush_cache(&mut *self.state.slots.get()) };
+        }
+
+        // The collector holds the thread list while waiting on counters, so the guard must be
+        // released before this thread can be removed.
+        self.vm
+            .threads
+            .lock()
+            .retain(|thread| !Arc::ptr_eq(thread, &self.state));
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn allocate_registers_objects_in_tlab() {
+    use crate::collect::VisitHeap;
+    use crate::trace::TraceContext;
+
+    struct Leaf(u64);
+
+    impl Trace for Leaf {
+        unsafe fn trace(&self, _: &mut TraceContext) {}
+    }
+
+    let vm = VirtualMachine::<Leaf>::test_builder().build();
+    let allocator = vm.attach_thread();
+
+    let ptrs = (0..16)
+        .map(|x| allocator.allocate(Leaf(x)).unwrap())
+        .collect::<Vec<_>>();
+
+    for (idx, ptr) in ptrs.iter().enumerate() {
+        assert_eq!(unsafe { (*ptr.direct_ptr()).0 }, idx as u64);
+    }
+
+    let tlab = unsafe { (*allocator.state.tlab.get()).as_ref().unwrap() };
+    let entries = tlab.iter_entries().collect::<Vec<_>>();
+    assert_eq!(entries.len(), ptrs.len());
+    assert!(ptrs
+        .iter()
+        .zip(entries)
+        .all(|(ptr, entry)| unsafe { ptr.direct_ptr() } as *mut () == entry.as_ptr()));
+}
+
+#[test]
+#[cfg(test)]
+fn minor_collection_evacuates_exhausted_nursery() {
+    use crate::collect::VisitHeap;
+    use crate::trace::TraceContext;
+
+    struct Leaf([u64; 7]);
+
+    impl Trace for Leaf {
+        unsafe fn trace(&self, _: &mut TraceContext) {}
+    }
+
+    let vm = VirtualMachine::<Leaf>::builder()
+        .nursery_size(16 << 10)
+        .tlab_size(4 << 10)
+        .survivor_size(2 << 10)
+        .build();
+    let allocator = vm.attach_thread();
+
+    let layout = <AnnotatedMixedHeap as HeapObjectSetup<Leaf>>::wrap_layout(Layout::new::<Leaf>());
+    let per_tlab = (4 << 10) / layout.size() as u64;
+
+    // Eden has room for three TLABs before a collection is required
+    let mut ptrs = (0..3 * per_tlab)
+        .map(|x| allocator.allocate(Leaf([x; 7])).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(vm.nursery.lock().retired().len(), 2);
+    assert!(vm.old.lock().regions().is_empty());
+
+    ptrs.push(allocator.allocate(Leaf([3 * per_tlab; 7])).unwrap());
+
+    let nursery = vm.nursery.lock();
+    let old = vm.old.lock();
+    assert_eq!(nursery.committed(), nursery.tlab_size());
+    assert!(nursery.retired().is_empty());
+
+    let survivors = nursery.survivors().iter_entries().count();
+    assert_eq!(survivors, (2 << 10) / layout.size());
+    assert_eq!(
+        survivors + old.iter_entries().count(),
+        3 * per_tlab as usize
+    );
+
+    for (idx, ptr) in ptrs.iter().enumerate() {
+        assert_eq!(unsafe { (*ptr.direct_ptr()).0 }, [idx as u64; 7]);
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn objects_are_promoted_after_tenuring_threshold() {
+    use crate::collect::VisitHeap;
+    use crate::mark::MarkWord;
+    use crate::trace::{HeapObjectLayout, TraceContext};
+
+    struct Leaf(u64);
+
+    impl Trace for Leaf {
+        unsafe fn trace(&self, _: &mut TraceContext) {}
+    }
+
+    let vm = VirtualMachine::<Leaf>::test_builder()
+        .tenuring(TenuringPolicy::Fixed(2))
+        .build();
+    let allocator = vm.attach_thread();
+
+    let ptrs = (0..8)
+        .map(|x| allocator.allocate(Leaf(x)).unwrap())
+        .collect::<Vec<_>>();
+    let age_of = |idx: usize| unsafe {
+        AnnotatedMixedHeap::mark(NonNull::new_unchecked(ptrs[idx].direct_ptr()).cast()).age()
+    };
+
+    for age in 1..=2 {
+        vm.collect_minor();
+        assert_eq!(age_of(0), age);
+        assert_eq!(vm.nursery.lock().survivors().iter_entries().count(), 8);
+    }
+
+    vm.collect_minor();
+    assert_eq!(age_of(0), 2);
+    assert!(vm
+        .nursery
+        .lock()
+        .survivors()
+        .iter_entries()
+        .next()
+        .is_none());
+    assert_eq!((&*vm.old.lock()).iter_entries().count(), 8);
+
+    for (idx, ptr) in ptrs.iter().enumerate() {
+        assert_eq!(unsafe { (*ptr.direct_ptr()).0 }, idx as u64);
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn write_barrier_remembers_old_to_young_references() {
+    use crate::ptr::GcCell;
+    use crate::trace::TraceContext;
+
+    enum Node {
+        Leaf(u64),
+        Link(GcCell<Node>),
+    }
+
+    impl Trace for Node {
+        unsafe fn trace(&self, cxt: &mut TraceContext) {
+            if let Node::Link(cell) = self {
+                cell.trace(cxt);
+            }
+        }
+    }
+
+    let vm = VirtualMachine::<Node>::test_builder()
+        .tenuring(TenuringPolicy::Fixed(1))
+        .build();
+    let allocator = vm.attach_thread();
+
+    let leaf = allocator.allocate(Node::Leaf(0)).unwrap();
+    let link = allocator.allocate(Node::Link(GcCell::new(leaf))).unwrap();
+    let cell = || match unsafe { &*link.direct_ptr() } {
+        Node::Link(cell) => cell,
+        Node::Leaf(_) => unreachable!(),
+    };
+    let is_dirty = || vm.old.lock().is_dirty(NonNull::from(cell()).cast());
+
+    // Promote both objects into the old generation
+    vm.collect_minor();
+    vm.collect_minor();
+    assert!(vm
+        .old
+        .lock()
+        .contains(NonNull::new(unsafe { link.direct_ptr() }).unwrap().cast()));
+    assert!(!is_dirty());
+
+    let young = allocator.allocate(Node::Leaf(7)).unwrap();
+    cell().set(&allocator, young);
+    assert!(is_dirty());
+
+    // The card stays dirty while the referenced object remains in the nursery
+    vm.collect_minor();
+    assert!(is_dirty());
+
+    vm.collect_minor();
+    assert!(!is_dirty());
+    assert!(matches!(
+        unsafe { &*cell().get().direct_ptr() },
+        Node::Leaf(7)
+    ));
+}
+
+#[test]
+#[cfg(test)]
+fn mutable_borrows_dirty_the_cards_of_old_objects() {
+    use crate::ptr::GcCell;
+    use crate::trace::TraceContext;
+
+    enum Node {
+        Leaf(u64),
+        Link(GcCell<Node>),
+    }
+
+    impl Trace for Node {
+        unsafe fn trace(&self, cxt: &mut TraceContext) {
+            if let Node::Link(cell) = self {
+                cell.trace(cxt);
+            }
+        }
+    }
+
+    let vm = VirtualMachine::<Node>::test_builder()
+        .tenuring(TenuringPolicy::Fixed(0))
+        .build();
+    let allocator = vm.attach_thread();
+    let scope = allocator.handle_scope();
+
+    let link = scope.allocate(Node::Leaf(0)).unwrap();
+    vm.collect_minor();
+    let is_dirty = || {
+        vm.old
+            .lock()
+            .is_dirty(NonNull::new(link.direct_ptr()).unwrap().cast())
+    };
+    assert!(!is_dirty());
+
+    // The store skips the write barrier, so the card is dirtied when the borrow ends
+    {
+        let inner = allocator.handle_scope();
+        let young = inner.allocate(Node::Leaf(7)).unwrap();
+        *allocator.borrow_mut(&link) = Node::Link(GcCell::new(young));
+    }
+    assert!(is_dirty());
+
+    // The young object is only reachable through the old object
+    vm.collect_minor();
+    assert_eq!(vm.live_slot_count(), 2);
+    match &*allocator.borrow_mut(&link) {
+        Node::Link(cell) => assert!(matches!(
+            unsafe { &*cell.get().direct_ptr() },
+            Node::Leaf(7)
+        )),
+        Node::Leaf(_) => unreachable!(),
+    };
+}
+
+#[test]
+#[cfg(test)]
+fn major_collection_sweeps_and_compacts_old_generation() {
+    use crate::trace::TraceContext;
+
+    struct Leaf([u64; 3]);
+
+    impl Trace for Leaf {
+        unsafe fn trace(&self, _: &mut TraceContext) {}
+    }
+
+    let vm = VirtualMachine::<Leaf>::test_builder()
+        .tenuring(TenuringPolicy::Fixed(0))
+        .build();
+    let allocator = vm.attach_thread();
+
+    let ptrs = (0..16)
+        .map(|x| allocator.allocate(Leaf([x; 3])).unwrap())
+        .collect::<Vec<_>>();
+    vm.collect_minor();
+
+    let layout = <AnnotatedMixedHeap as HeapObjectSetup<Leaf>>::wrap_layout(Layout::new::<Leaf>());
+    assert_eq!(vm.old.lock().used(), 16 * layout.size());
+
+    // Drop every other object by releasing its slot
+    unsafe {
+        let dead = ptrs.iter().step_by(2).map(|ptr| ptr.slot().cast());
+        vm.ref_table.free_slots(dead);
+    }
+
+    vm.collect_major(false);
+    {
+        let old = vm.old.lock();
+        let walked = unsafe { old.regions()[0].walk().count() };
+        assert_eq!(walked, 8);
+        assert_eq!((&*old).iter_entries().count(), 8);
+        assert_eq!(old.used(), 16 * layout.size());
+    }
+
+    vm.collect_major(true);
+    assert_eq!(vm.old.lock().used(), 8 * layout.size());
+
+    for (idx, ptr) in ptrs.iter().enumerate().skip(1).step_by(2) {
+        assert_eq!(unsafe { (*ptr.direct_ptr()).0 }, [idx as u64; 3]);
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn raw_edges_keep_large_objects_in_place() {
+    use crate::trace::TraceContext;
+
+    struct Large {
+        raw: Option<DirectObjUnknown>,
+        data: [u64; 64],
+    }
+
+    impl Trace for Large {
+        unsafe fn trace(&self, cxt: &mut TraceContext) {
+            if let Some(object) = self.raw {
+                cxt.visit_raw(object);
+            }
+        }
+    }
+
+    let vm = VirtualMachine::<Large>::test_builder()
+        .large_object_threshold(256)
+        .build();
+    let allocator = vm.attach_thread();
+    assert!(allocator.allocates_large_objects());
+
+    let scope = allocator.handle_scope();
+    let target = {
+        let inner = allocator.handle_scope();
+        let root = inner
+            .allocate(Large {
+                raw: None,
+                data: [7; 64],
+            })
+            .unwrap();
+        NonNull::new(root.direct_ptr()).unwrap().cast()
+    };
+    scope
+        .allocate(Large {
+            raw: Some(target),
+            data: [0; 64],
+        })
+        .unwrap();
+
+    // The target is only reachable through the raw edge, which neither collection may break
+    vm.collect_minor();
+    vm.collect_major(true);
+    assert_eq!(vm.live_slot_count(), 2);
+    assert_eq!(unsafe { (*target.cast::<Large>().as_ptr()).data }, [7; 64]);
+}
+
+#[test]
+#[cfg(test)]
+fn large_allocations_collect_when_the_heap_is_full() {
+    let vm = VirtualMachine::<[u64; 1024]>::test_builder()
+        .old_size(64 << 10)
+        .max_heap_size(1 << 20)
+        .build();
+    let allocator = vm.attach_thread();
+
+    // Far more garbage than fits in the heap at once
+    for _ in 0..512 {
+        let scope = allocator.handle_scope();
+        scope.allocate([0; 1024]).unwrap();
+    }
+
+    // The old generation and the large object space draw from the same budget
+    let reserved = vm.old.lock().committed() + vm.large_objects.lock().allocated();
+    assert!(reserved <= vm.max_heap_size() - (64 << 10));
+}
+
+#[test]
+#[cfg(test)]
+fn exhausting_the_heap_with_live_objects_fails_allocation() {
+    let vm = VirtualMachine::<[u64; 9]>::test_builder()
+        .old_size(16 << 10)
+        .max_heap_size(256 << 10)
+        .build();
+    let allocator = vm.attach_thread();
+
+    // Every object stays rooted, so the heap eventually has no room left to promote them
+    let scope = allocator.handle_scope();
+    let mut ptrs = Vec::new();
+    let error = loop {
+        match scope.allocate([ptrs.len() as u64; 9]) {
+            Ok(ptr) => ptrs.push(ptr),
+            Err(error) => break error,
+        }
+        assert!(ptrs.len() < 256 << 10, "allocations never ran out of room");
+    };
+
+    assert_eq!(error.layout(), Layout::new::<[u64; 9]>());
+    for (idx, ptr) in ptrs.iter().enumerate() {
+        assert_eq!(*allocator.borrow(ptr), [idx as u64; 9]);
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn major_collections_free_the_old_generation_before_evacuating() {
+    let vm = VirtualMachine::<[u64; 9]>::test_builder()
+        .tenuring(TenuringPolicy::Fixed(0))
+        .old_size(16 << 10)
+        .max_heap_size(256 << 10)
+        .build();
+    let allocator = vm.attach_thread();
+    let headroom = || vm.old.lock().promotion_headroom(vm.large_object_threshold);
+
+    // Promote objects which die straight afterwards until the old generation is nearly full
+    while headroom() >= 8 << 10 {
+        let scope = allocator.handle_scope();
+        for _ in 0..64 {
+            scope.allocate([0; 9]).unwrap();
+        }
+        vm.collect_minor();
+    }
+
+    let scope = allocator.handle_scope();
+    let ptrs = (0..256u64)
+        .map(|idx| scope.allocate([idx; 9]).unwrap())
+        .collect::<Vec<_>>();
+    vm.collect_major(false);
+
+    // The garbage was swept first, which made room to promote the whole nursery
+    assert_eq!(vm.nursery.lock().used(), 0);
+    for (idx, ptr) in ptrs.iter().enumerate() {
+        assert_eq!(*allocator.borrow(ptr), [idx as u64; 9]);
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn native_threads_do_not_block_collections() {
+    use std::sync::mpsc::channel;
+
+    let vm = VirtualMachine::<u64>::test_builder().build();
+    let (entered, native) = channel();
+    let (release, released) = channel();
+
+    let vm = &vm;
+    thread::scope(|scope| {
+        let worker = scope.spawn(move || {
+            let allocator = vm.attach_thread();
+            let ptr = allocator.allocate(7).unwrap();
+
+            // Block while holding the counter, as a read inside an IncrementGuard would
+            let _guard = allocator.state.counter.increment_or_savepoint();
+            let guard = allocator.enter_native();
+            assert!(allocator.in_native());
+            entered.send(thread::current().id()).unwrap();
+            released.recv().unwrap();
+            guard.exit_native();
+
+            assert!(!allocator.in_native());
+            assert_eq!(unsafe { *ptr.direct_ptr() }, 7);
+        });
+
+        let id = native.recv().unwrap();
+        assert_eq!(vm.attached_threads(), [id]);
+
+        vm.collect_minor();
+        release.send(()).unwrap();
+        worker.join().unwrap();
+    });
+
+    assert!(vm.attached_threads().is_empty());
 }
@@ -0,0 +1,268 @@
+//! Safe access to the contents of objects on the heap.
+//!
+//! A `GcRef` or `GcRefMut` holds an entry in its thread's access counter, so the collector can not
+//! move or free the object while it is borrowed. Borrows are tracked per object by the VM, so the
+//! usual rules of `RefCell` are enforced across every thread: an object may either be borrowed
+//! immutably any number of times or mutably once.
+
+use crate::collect::IncrementGuard;
+use crate::mem::card::CardTables;
+use crate::ptr::DirectObjUnknown;
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+/// The reason an object could not be borrowed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BorrowError {
+    /// The object is already borrowed mutably
+    MutablyBorrowed,
+    /// The object is already borrowed, so it can not be borrowed mutably
+    Borrowed,
+    /// The object was allocated by a different VM
+    ForeignVm,
+}
+
+impl Display for BorrowError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BorrowError::MutablyBorrowed => write!(f, "object is already mutably borrowed"),
+            BorrowError::Borrowed => write!(f, "object is already borrowed"),
+            BorrowError::ForeignVm => write!(f, "object belongs to another vm"),
+        }
+    }
+}
+
+impl Error for BorrowError {}
+
+/// The number of borrows of every borrowed object, keyed by slot. A count of -1 marks a mutable
+/// borrow.
+#[derive(Debug, Default)]
+pub(crate) struct BorrowTable {
+    borrows: Mutex<HashMap<NonNull<DirectObjUnknown>, isize>>,
+}
+
+/// Slots are only used as keys and are never read through the table
+unsafe impl Send for BorrowTable {}
+unsafe impl Sync for BorrowTable {}
+
+impl BorrowTable {
+    pub(crate) fn borrow(&self, slot: NonNull<DirectObjUnknown>) -> Result<(), BorrowError> {
+        let mut borrows = self.borrows.lock();
+        let count = borrows.entry(slot).or_insert(0);
+
+        if *count < 0 {
+            return Err(BorrowError::MutablyBorrowed);
+        }
+
+        *count += 1;
+        Ok(())
+    }
+
+    pub(crate) fn borrow_mut(&self, slot: NonNull<DirectObjUnknown>) -> Result<(), BorrowError> {
+        let mut borrows = self.borrows.lock();
+
+        match borrows.get(&slot) {
+            Some(count) if *count < 0 => Err(BorrowError::MutablyBorrowed),
+            Some(_) => Err(BorrowError::Borrowed),
+            None => {
+                borrows.insert(slot, -1);
+                Ok(())
+            }
+        }
+    }
+
+    fn release(&self, slot: NonNull<DirectObjUnknown>) {
+        let mut borrows = self.borrows.lock();
+        let count = borrows.get_mut(&slot).expect("object was not borrowed");
+
+        if *count > 1 {
+            *count -= 1;
+        } else {
+            borrows.remove(&slot);
+        }
+    }
+}
+
+/// Releases a borrow of an object along with the thread's entry in its access counter
+pub(crate) struct BorrowGuard<'a> {
+    table: &'a BorrowTable,
+    slot: NonNull<DirectObjUnknown>,
+    /// The number of borrows held by the borrowing thread
+    thread_borrows: &'a Cell<usize>,
+    _guard: IncrementGuard<'a>,
+}
+
+impl<'a> BorrowGuard<'a> {
+    /// Record a borrow which has been granted by `table`. The thread must already be holding the
+    /// counter through `guard`.
+    pub(crate) fn new(
+        table: &'a BorrowTable,
+        slot: NonNull<DirectObjUnknown>,
+        thread_borrows: &'a Cell<usize>,
+        guard: IncrementGuard<'a>,
+    ) -> Self {
+        thread_borrows.set(thread_borrows.get() + 1);
+
+        BorrowGuard {
+            table,
+            slot,
+            thread_borrows,
+            _guard: guard,
+        }
+    }
+}
+
+impl<'a> Drop for BorrowGuard<'a> {
+    fn drop(&mut self) {
+        self.table.release(self.slot);
+        self.thread_borrows.set(self.thread_borrows.get() - 1);
+    }
+}
+
+/// An immutable borrow of an object on the heap. The object can not be moved or collected until
+/// the borrow is dropped, so collections started by other threads will wait for it.
+pub struct GcRef<'a, T> {
+    object: NonNull<T>,
+    _borrow: BorrowGuard<'a>,
+    _phantom: PhantomData<&'a T>,
+}
+
+impl<'a, T> GcRef<'a, T> {
+    /// # Safety
+    /// `object` must point to the live object borrowed by `borrow`.
+    pub(crate) unsafe fn new(object: NonNull<T>, borrow: BorrowGuard<'a>) -> Self {
+        GcRef {
+            object,
+            _borrow: borrow,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, T> Deref for GcRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The object can not move while the counter is held, and no mutable borrow exists.
+        // Other threads may read the object at the same time through their own borrows, which is
+        // why `ThreadAllocator::borrow` requires the object to be `Sync`
+        unsafe { self.object.as_ref() }
+    }
+}
+
+/// A mutable borrow of an object on the heap. No other borrows of the object may exist until it
+/// is dropped.
+pub struct GcRefMut<'a, T> {
+    object: NonNull<T>,
+    /// The cards of the old generation, which are dirtied once the borrow ends
+    cards: &'a CardTables,
+    _borrow: BorrowGuard<'a>,
+    _phantom: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> GcRefMut<'a, T> {
+    /// # Safety
+    /// `object` must point to the live object mutably borrowed by `borrow`.
+    pub(crate) unsafe fn new(
+        object: NonNull<T>,
+        cards: &'a CardTables,
+        borrow: BorrowGuard<'a>,
+    ) -> Self {
+        GcRefMut {
+            object,
+            cards,
+            _borrow: borrow,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, T> Deref for GcRefMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: The object can not move while the counter is held
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<'a, T> DerefMut for GcRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: The borrow table guarantees this is the only borrow of the object
+        unsafe { self.object.as_mut() }
+    }
+}
+
+/// References stored through a mutable borrow skip the write barrier, so every card holding the
+/// object is dirtied instead. The counter is still held, so the card tables can not change.
+impl<'a, T> Drop for GcRefMut<'a, T> {
+    fn drop(&mut self) {
+        let start = self.object.as_ptr() as usize;
+        self.cards.dirty_range(start..start + size_of::<T>());
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn borrows_are_checked_and_block_collections() {
+    use crate::alloc::VirtualMachine;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    let vm = VirtualMachine::<u64>::test_builder().build();
+    let allocator = vm.attach_thread();
+    let scope = allocator.handle_scope();
+    let ptr = scope.allocate(1).unwrap();
+
+    // Roots from another VM are rejected rather than read from this VM's reference table
+    let other = VirtualMachine::<u64>::test_builder().build();
+    let other_allocator = other.attach_thread();
+    assert_eq!(
+        other_allocator.try_borrow(&ptr).err(),
+        Some(BorrowError::ForeignVm)
+    );
+
+    {
+        let first = allocator.borrow(&ptr);
+        let second = allocator.borrow(&ptr);
+        assert_eq!(*first + *second, 2);
+        assert_eq!(
+            allocator.try_borrow_mut(&ptr).err(),
+            Some(BorrowError::Borrowed)
+        );
+    }
+
+    let mut value = allocator.borrow_mut(&ptr);
+    *value = 2;
+    assert_eq!(
+        allocator.try_borrow(&ptr).err(),
+        Some(BorrowError::MutablyBorrowed)
+    );
+
+    // The collector has to wait for the borrow to be released before it can move the object
+    let collected = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let collector = scope.spawn(|| {
+            vm.collect_minor();
+            collected.store(true, Ordering::SeqCst);
+        });
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(!collected.load(Ordering::SeqCst));
+        assert_eq!(*value, 2);
+
+        drop(value);
+        collector.join().unwrap();
+    });
+
+    assert!(collected.load(Ordering::SeqCst));
+    assert_eq!(*allocator.borrow(&ptr), 2);
+}
@@ -0,0 +1,140 @@
+use crate::mark::MarkWord;
+use crate::ptr::DirectObjUnknown;
+use crate::trace::{Edge, HeapObjectLayout, TraceContext};
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+
+/// Marks every object reachable from a set of roots. Objects are traced using a work-list held by
+/// the `TraceContext` so deeply nested object graphs can not overflow the stack.
+pub struct Marker<L> {
+    cxt: TraceContext,
+    reachable: Vec<DirectObjUnknown>,
+    /// The slots of ephemeron keys which have not been marked yet, along with their values
+    ephemerons: Vec<(NonNull<DirectObjUnknown>, NonNull<DirectObjUnknown>)>,
+    _phantom: PhantomData<L>,
+}
+
+impl<L> Default for Marker<L> {
+    fn default() -> Self {
+        Marker {
+            cxt: TraceContext::default(),
+            reachable: Vec::new(),
+            ephemerons: Vec::new(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<L: HeapObjectLayout> Marker<L> {
+    /// Mark every object reachable from the given roots. Objects which were already marked are
+    /// not traced again. The values of ephemerons are only marked once their keys have been
+    /// marked, so marking repeats until no more ephemerons are found with reachable keys. Ephemerons
+    /// whose keys are still unmarked are revisited by later calls.
+    ///
+    /// # Safety
+    /// Every root must be a direct pointer to a live object laid out according to `L`, and the
+    /// heap must not be mutated until marking has finished.
+    pub unsafe fn mark_from<I>(&mut self, roots: I)
+    where
+        I: IntoIterator<Item = DirectObjUnknown>,
+    {
+        for root in roots {
+            self.cxt.push_edge(Edge::Object(root));
+        }
+
+        loop {
+            while let Some(object) = self.cxt.pop_mark() {
+                if L::mark(object).set_mark() {
+                    continue;
+                }
+
+                self.reachable.push(object);
+                L::trace(object, &mut self.cxt);
+            }
+
+            self.ephemerons.append(&mut self.cxt.take_ephemerons());
+            let before = self.ephemerons.len();
+            let cxt = &mut self.cxt;
+            self.ephemerons.retain(|&(key, value)| {
+                if !L::mark(key.read()).is_marked() {
+                    return true;
+                }
+
+                cxt.push_edge(Edge::Slot(value));
+                false
+            });
+
+            if self.ephemerons.len() == before {
+                return;
+            }
+        }
+    }
+
+    /// The objects which have been marked so far in the order they were reached
+    pub fn reachable(&self) -> &[DirectObjUnknown] {
+        &self.reachable
+    }
+
+    pub fn into_reachable(self) -> Vec<DirectObjUnknown> {
+        self.reachable
+    }
+
+    /// Remove the mark from every object reached by this marker
+    ///
+    /// # Safety
+    /// The objects reached by this marker must still be live.
+    pub unsafe fn unmark_reachable(&self) {
+        for object in &self.reachable {
+            L::mark(*object).unmark();
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn marking_follows_traced_edges() {
+    use crate::alloc::VirtualMachine;
+    use crate::ptr::GcPtr;
+    use crate::trace::{AnnotatedMixedHeap, Trace};
+    use std::ptr::NonNull;
+
+    struct Node(Option<GcPtr<Node>>);
+
+    impl Trace for Node {
+        unsafe fn trace(&self, cxt: &mut TraceContext) {
+            if let Some(next) = &self.0 {
+                cxt.visit(next);
+            }
+        }
+    }
+
+    let vm = VirtualMachine::<Node>::builder().build();
+    let allocator = vm.attach_thread();
+
+    let direct = |ptr: &GcPtr<Node>| unsafe { NonNull::new_unchecked(ptr.direct_ptr()).cast() };
+
+    let c = allocator.allocate(Node(None)).unwrap();
+    let c_direct = direct(&c);
+    let b = allocator.allocate(Node(Some(c))).unwrap();
+    let b_direct = direct(&b);
+    let a = allocator.allocate(Node(Some(b))).unwrap();
+    let a_direct = direct(&a);
+    let unlinked = allocator.allocate(Node(None)).unwrap();
+
+    unsafe {
+        let mut marker = Marker::<AnnotatedMixedHeap>::default();
+        marker.mark_from([a_direct]);
+        assert_eq!(marker.reachable(), &[a_direct, b_direct, c_direct]);
+        assert!(!AnnotatedMixedHeap::mark(direct(&unlinked)).is_marked());
+
+        marker.unmark_reachable();
+        assert!(!AnnotatedMixedHeap::mark(a_direct).is_marked());
+    }
+
+    // Every live slot is a root, so all objects are reached exactly once
+    let reachable = vm.collect();
+    assert_eq!(reachable.len(), 4);
+    assert!(reachable
+        .iter()
+        .all(|object| unsafe { !AnnotatedMixedHeap::mark(*object).is_marked() }));
+}
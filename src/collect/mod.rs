@@ -1,16 +1,28 @@
 use crate::mark::MarkWord;
 use crate::ptr::DirectObjUnknown;
 use crate::trace::HeapObjectLayout;
-use std::hint::spin_loop;
-use std::mem::size_of;
-use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+pub mod marker;
+pub mod safepoint;
+pub mod scavenge;
+
+/// Provides access to the objects stored within a region of the heap.
+///
+/// # Safety
+/// Every entry produced by `iter_entries` must be a valid direct pointer to an object laid out
+/// according to `Self::Layout`.
 pub unsafe trait VisitHeap: Sized {
     type Layout: HeapObjectLayout;
     type EntryIter: IntoIterator<Item = DirectObjUnknown>;
 
     fn iter_entries(self) -> Self::EntryIter;
 
+    /// Remove the mark from every object in this heap.
+    ///
+    /// # Safety
+    /// No other thread may be marking or tracing objects within this heap at the same time.
     unsafe fn unmark_heap(self) {
         for entry in self.iter_entries() {
             Self::Layout::mark(entry).unmark();
@@ -19,10 +31,13 @@ pub unsafe trait VisitHeap: Sized {
 }
 
 /// A counter used for keeping track of the number of entries within a heap region and closing off
-/// access before a garbage collection sweep.
+/// access before a garbage collection sweep. Threads which need to wait for the counter to open or
+/// close are parked rather than spinning.
 #[derive(Debug, Default)]
 pub struct AccessCounter {
     counter: AtomicUsize,
+    lock: Mutex<()>,
+    changed: Condvar,
 }
 
 /// AccessCounter is safe since it only performs atomic operations on its contents
@@ -30,10 +45,10 @@ unsafe impl Sync for AccessCounter {}
 
 impl AccessCounter {
     /// The close mask is simply the highest bit
-    const CLOSE_MASK: usize = 1usize << (8 * size_of::<usize>() - 1);
+    const CLOSE_MASK: usize = 1usize << (usize::BITS - 1);
     const COUNT_MASK: usize = !Self::CLOSE_MASK;
 
-    pub fn close_counter(&self) -> CloseGuard {
+    pub fn close_counter(&self) -> CloseGuard<'_> {
         unsafe {
             self.request_close();
         }
@@ -44,14 +59,14 @@ impl AccessCounter {
     /// should be prefered over increment when possible, but running overlapping
     /// increment_or_savepoint on a single thread may result in a deadlock. When an overlap may
     /// occur, increment can be used for subsequent calls.
-    pub fn increment_or_savepoint(&self) -> IncrementGuard {
+    pub fn increment_or_savepoint(&self) -> IncrementGuard<'_> {
         unsafe {
             self.blocking_enter();
         }
         IncrementGuard { inner: self }
     }
 
-    pub fn increment(&self) -> IncrementGuard {
+    pub fn increment(&self) -> IncrementGuard<'_> {
         unsafe {
             self.forced_entry();
         }
@@ -60,16 +75,19 @@ impl AccessCounter {
 
     /// Request that this counter be closed. If the counter is already in the process of being
     /// closed, this function will block until it can be closed in favor of this thread.
+    ///
+    /// # Safety
+    /// The caller becomes responsible for releasing the close request with
+    /// `release_close_request`. Prefer `close_counter` which does this automatically.
     pub unsafe fn request_close(&self) {
         let mut prev = self.counter.load(Ordering::SeqCst);
         loop {
             if prev & Self::CLOSE_MASK != 0 {
-                spin_loop();
-                prev = self.counter.load(Ordering::SeqCst);
+                prev = self.park_until(|value| value & Self::CLOSE_MASK == 0);
                 continue;
             }
 
-            let new = prev & Self::CLOSE_MASK;
+            let new = prev | Self::CLOSE_MASK;
             match self
                 .counter
                 .compare_exchange_weak(prev, new, Ordering::SeqCst, Ordering::SeqCst)
@@ -83,21 +101,43 @@ impl AccessCounter {
     /// Blocks until the counter is completely closed. However, this function will not initiate the
     /// close.
     pub fn block_until_closed(&self) {
+        self.park_until(|value| value == Self::CLOSE_MASK);
+    }
+
+    /// Park the current thread until the value of the counter satisfies `ready`. Returns the value
+    /// which was observed.
+    fn park_until<F: Fn(usize) -> bool>(&self, ready: F) -> usize {
+        let mut lock = self.lock.lock();
         loop {
-            match self.counter.load(Ordering::SeqCst) {
-                Self::CLOSE_MASK => return,
-                _ => spin_loop(),
+            let value = self.counter.load(Ordering::SeqCst);
+            if ready(value) {
+                return value;
             }
+
+            self.changed.wait(&mut lock);
         }
     }
 
+    /// Wake every thread parked on this counter. The lock is taken so a thread can not miss the
+    /// change between checking the counter and parking.
+    fn unpark_all(&self) {
+        drop(self.lock.lock());
+        self.changed.notify_all();
+    }
+
     /// Released a close request. Should only be called by closer.
+    ///
+    /// # Safety
+    /// The caller must be the thread which requested the counter be closed.
     pub unsafe fn release_close_request(&self) {
-        self.counter
+        let _ = self
+            .counter
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |counter| {
                 assert_eq!(counter & Self::CLOSE_MASK, Self::CLOSE_MASK);
                 Some(counter & Self::COUNT_MASK)
             });
+
+        self.unpark_all();
     }
 
     /// Attempt to increment the counter to gain entry. Ignores if a thread is attempting to close
@@ -108,6 +148,9 @@ impl AccessCounter {
     /// This option is available to prevent deadlocks when two or more items need to enter the
     /// counter at the same time. Using blocking_enter should always be preferred unless there is
     /// an existing entry on that thread which has yet to be released.
+    ///
+    /// # Safety
+    /// Every entry must later be paired with a call to `exit_counter`.
     pub unsafe fn forced_entry(&self) {
         let gained_entry = self
             .counter
@@ -130,17 +173,26 @@ impl AccessCounter {
 
     /// Increments the counter to gain entry. If the counter is being closed, it will block until
     /// it reopens.
+    ///
+    /// # Safety
+    /// Every entry must later be paired with a call to `exit_counter`.
     pub unsafe fn blocking_enter(&self) {
+        self.enter_many(1)
+    }
+
+    /// Gain `count` entries at once, parking while the counter is closed.
+    ///
+    /// # Safety
+    /// Every entry must later be paired with a call to `exit_counter`.
+    unsafe fn enter_many(&self, count: usize) {
         let mut prev = self.counter.load(Ordering::SeqCst);
         loop {
             if prev & Self::CLOSE_MASK != 0 {
-                // TODO: Should this be switched to a system which allows the current thread to sleep until it is acquired?
-                spin_loop();
-                prev = self.counter.load(Ordering::SeqCst);
+                prev = self.park_until(|value| value & Self::CLOSE_MASK == 0);
                 continue;
             }
 
-            let new_count = prev + 1;
+            let new_count = prev + count;
             debug_assert_eq!(new_count & Self::CLOSE_MASK, 0);
             match self.counter.compare_exchange_weak(
                 prev,
@@ -154,13 +206,47 @@ impl AccessCounter {
         }
     }
 
+    /// Release every entry held on this counter so it may be closed while the thread owning it is
+    /// blocked outside of the heap. Returns the number of entries which were released.
+    ///
+    /// # Safety
+    /// Every entry on this counter must belong to the calling thread, which must not touch the
+    /// heap until the entries are regained with `reenter`.
+    pub unsafe fn release_entries(&self) -> usize {
+        let prev = self.counter.fetch_and(Self::CLOSE_MASK, Ordering::SeqCst);
+
+        if prev & Self::CLOSE_MASK != 0 && prev & Self::COUNT_MASK != 0 {
+            self.unpark_all();
+        }
+        prev & Self::COUNT_MASK
+    }
+
+    /// Regain entries released by `release_entries`, parking while the counter is closed.
+    ///
+    /// # Safety
+    /// `count` must be the value returned by the matching call to `release_entries`.
+    pub unsafe fn reenter(&self, count: usize) {
+        if count > 0 {
+            self.enter_many(count);
+        }
+    }
+
     /// Decrease the counter after finishing work
+    ///
+    /// # Safety
+    /// Must only be called to release an entry previously gained by this thread.
     pub unsafe fn exit_counter(&self) {
-        self.counter
+        let prev = self
+            .counter
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
                 debug_assert!(count & Self::COUNT_MASK > 0);
                 Some(count - 1)
             });
+
+        // The last thread to leave a closing counter wakes the closer
+        if prev == Ok(Self::CLOSE_MASK | 1) {
+            self.unpark_all();
+        }
     }
 }
 /// A simple wrapper that ensures that when a counter is incremented, it gets decremented once
@@ -0,0 +1,136 @@
+use crate::collect::{AccessCounter, CloseGuard};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Timings for how long the collector had to wait for every thread to reach a safepoint
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SafepointStats {
+    /// The number of times the world has been stopped
+    pub count: usize,
+    pub total: Duration,
+    pub max: Duration,
+    pub last: Duration,
+}
+
+impl SafepointStats {
+    fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+        self.last = elapsed;
+    }
+
+    /// The average time taken to reach a safepoint
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => self.total / count as u32,
+        }
+    }
+}
+
+/// Coordinates stopping every mutator thread for a collection. While a safepoint is requested,
+/// mutators which poll `is_requested` or try to enter their access counter are parked until the
+/// collector releases the world.
+#[derive(Debug, Default)]
+pub struct Safepoint {
+    requested: AtomicBool,
+    stats: Mutex<SafepointStats>,
+}
+
+impl Safepoint {
+    /// A cheap check for whether a collector is waiting for threads to reach a safepoint
+    #[inline]
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Close every counter and park until all threads have left them. The world is released when
+    /// the returned guard is dropped.
+    pub fn synchronize<'a, I>(&'a self, counters: I) -> StoppedWorld<'a>
+    where
+        I: IntoIterator<Item = &'a AccessCounter>,
+    {
+        let start = Instant::now();
+        self.requested.store(true, Ordering::SeqCst);
+
+        let guards = counters
+            .into_iter()
+            .map(AccessCounter::close_counter)
+            .collect::<Vec<_>>();
+
+        for guard in &guards {
+            guard.block_until_closed();
+        }
+
+        self.stats.lock().record(start.elapsed());
+        StoppedWorld {
+            safepoint: self,
+            _guards: guards,
+        }
+    }
+
+    pub fn stats(&self) -> SafepointStats {
+        *self.stats.lock()
+    }
+}
+
+/// Holds every thread at a safepoint. Dropping this wakes all parked threads.
+pub struct StoppedWorld<'a> {
+    safepoint: &'a Safepoint,
+    _guards: Vec<CloseGuard<'a>>,
+}
+
+impl<'a> Drop for StoppedWorld<'a> {
+    fn drop(&mut self) {
+        // The flag is lowered first so woken threads do not immediately poll into a safepoint
+        self.safepoint.requested.store(false, Ordering::SeqCst);
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn threads_are_parked_until_released() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+
+    let counter = Arc::new(AccessCounter::default());
+    let safepoint = Safepoint::default();
+    let entered = Arc::new(AtomicUsize::new(0));
+
+    // A mutator which only reaches the safepoint after some time
+    let guard = counter.increment_or_savepoint();
+    let busy = thread::spawn({
+        let counter = Arc::clone(&counter);
+        move || {
+            thread::sleep(Duration::from_millis(20));
+            unsafe { counter.exit_counter() };
+        }
+    });
+    std::mem::forget(guard);
+
+    let stopped = safepoint.synchronize([&*counter]);
+    busy.join().unwrap();
+    assert!(safepoint.is_requested());
+    assert!(safepoint.stats().last >= Duration::from_millis(20));
+
+    let parked = thread::spawn({
+        let counter = Arc::clone(&counter);
+        let entered = Arc::clone(&entered);
+        move || {
+            let _guard = counter.increment_or_savepoint();
+            entered.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(entered.load(Ordering::SeqCst), 0);
+
+    drop(stopped);
+    parked.join().unwrap();
+    assert_eq!(entered.load(Ordering::SeqCst), 1);
+    assert!(!safepoint.is_requested());
+    assert_eq!(safepoint.stats().count, 1);
+}
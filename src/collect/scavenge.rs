@@ -0,0 +1,215 @@
+use crate::mark::{MarkWord, MAX_AGE};
+use crate::mem::block::OwnedMemoryBlock;
+use crate::mem::old::OldGeneration;
+use crate::mem::HeapRegion;
+use crate::ptr::DirectObjUnknown;
+use crate::trace::{Edge, HeapObjectLayout, TraceContext, TracePhase};
+#[cfg(feature = "drop_heap")]
+use std::collections::HashSet;
+use std::ops::Range;
+use std::ptr::NonNull;
+
+/// The number of bytes surviving a minor collection by their age, used to choose the tenuring
+/// threshold in the same way as HotSpot.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AgeTable {
+    sizes: [usize; MAX_AGE + 1],
+}
+
+impl AgeTable {
+    /// Record that `size` bytes were copied into the to-space with the given age
+    pub fn add(&mut self, age: usize, size: usize) {
+        self.sizes[age.min(MAX_AGE)] += size;
+    }
+
+    /// The number of bytes recorded for a given age
+    pub fn size_of(&self, age: usize) -> usize {
+        self.sizes[age.min(MAX_AGE)]
+    }
+
+    /// Pick the lowest age where the objects of that age or younger would take up more than
+    /// `desired_survivor_size` bytes, capped at `max_threshold`. Objects which reach this age are
+    /// promoted so the survivor spaces do not overflow.
+    pub fn compute_threshold(&self, desired_survivor_size: usize, max_threshold: usize) -> usize {
+        let mut total = 0;
+        let mut age = 1;
+
+        while age <= MAX_AGE {
+            total += self.sizes[age];
+            if total > desired_survivor_size {
+                break;
+            }
+            age += 1;
+        }
+
+        age.min(max_threshold)
+    }
+}
+
+/// Evacuates live objects out of the young generation. Objects younger than the tenuring threshold
+/// are copied into the to-space and have their age increased. Objects which have reached the
+/// threshold, or which do not fit within the to-space, are promoted into the old generation.
+///
+/// Every object is referenced through exactly one slot in the reference table, so moving an object
+/// only requires rewriting its slot. Once a slot has been rewritten it points outside of the
+/// evacuated spaces, so any later visits of the same slot are skipped.
+pub struct Scavenger<'a, L> {
+    cxt: TraceContext,
+    young: Vec<Range<usize>>,
+    to: &'a mut HeapRegion<OwnedMemoryBlock, L>,
+    old: &'a mut OldGeneration<L>,
+    tenuring_threshold: usize,
+    ages: AgeTable,
+    promoted: usize,
+    promoted_objects: Vec<DirectObjUnknown>,
+    /// The originals of every copied object, so the dead objects left behind can be dropped
+    #[cfg(feature = "drop_heap")]
+    evacuated: HashSet<DirectObjUnknown>,
+}
+
+impl<'a, L: HeapObjectLayout> Scavenger<'a, L> {
+    /// Create a scavenger which will evacuate objects out of the given spaces. Objects which have
+    /// survived `tenuring_threshold` collections are promoted into the old generation.
+    pub fn new(
+        to: &'a mut HeapRegion<OwnedMemoryBlock, L>,
+        old: &'a mut OldGeneration<L>,
+        tenuring_threshold: usize,
+    ) -> Self {
+        Scavenger {
+            cxt: TraceContext::new(TracePhase::Copy),
+            young: Vec::new(),
+            to,
+            old,
+            tenuring_threshold,
+            ages: AgeTable::default(),
+            promoted: 0,
+            promoted_objects: Vec::new(),
+            #[cfg(feature = "drop_heap")]
+            evacuated: HashSet::new(),
+        }
+    }
+
+    /// Mark a range of memory as part of the young generation so objects within it are evacuated
+    pub fn add_young(&mut self, range: Range<usize>) {
+        let idx = self
+            .young
+            .partition_point(|existing| existing.start < range.start);
+        self.young.insert(idx, range);
+    }
+
+    /// Check if an object is within the young generation
+    fn is_young(&self, object: DirectObjUnknown) -> bool {
+        let addr = object.as_ptr() as usize;
+        let idx = self.young.partition_point(|range| range.start <= addr);
+
+        idx > 0 && self.young[idx - 1].contains(&addr)
+    }
+
+    /// Treat every reference held by an old object as a root. Used for objects on dirty cards which
+    /// may refer to young objects.
+    ///
+    /// # Safety
+    /// Every object must be live. All threads must be stopped for the duration of the scavenge.
+    pub unsafe fn add_old_roots(&mut self, objects: &[DirectObjUnknown]) {
+        for object in objects {
+            L::trace(*object, &mut self.cxt);
+        }
+    }
+
+    /// Evacuate every young object reachable from the given slots.
+    ///
+    /// # Safety
+    /// Every root must be a slot which is assigned to a live object. All threads must be stopped
+    /// for the duration of the scavenge.
+    ///
+    /// # Panics
+    /// Panics if the old generation runs out of room while promoting objects. Callers check the
+    /// promotion headroom of the old generation beforehand.
+    pub unsafe fn scavenge<I>(&mut self, roots: I)
+    where
+        I: IntoIterator<Item = NonNull<DirectObjUnknown>>,
+    {
+        for slot in roots {
+            self.cxt.push_edge(Edge::Slot(slot));
+        }
+
+        while let Some(edge) = self.cxt.pop_copy() {
+            match edge {
+                Edge::Slot(slot) => {
+                    let object = slot.as_ptr().read();
+                    if self.is_young(object) {
+                        let copy = self.evacuate(object);
+                        slot.as_ptr().write(copy);
+                        L::trace(copy, &mut self.cxt);
+                    }
+                }
+                // Raw edges only refer to large objects, which are never young
+                Edge::Object(_) => {}
+            }
+        }
+    }
+
+    unsafe fn evacuate(&mut self, object: DirectObjUnknown) -> DirectObjUnknown {
+        let size = L::layout(object).size();
+        #[cfg(feature = "drop_heap")]
+        self.evacuated.insert(object);
+
+        if L::mark(object).age() < self.tenuring_threshold {
+            if let Some(copy) = self.to.copy_object(object) {
+                let mark = L::mark(copy);
+                mark.increment_age();
+                self.ages.add(mark.age(), size);
+                return copy;
+            }
+        }
+
+        let copy = self
+            .old
+            .copy_object(object)
+            .expect("old generation lacks the headroom to promote every young object");
+        self.promoted += size;
+        self.promoted_objects.push(copy);
+        copy
+    }
+
+    /// The number of bytes copied into the to-space by their age
+    pub fn ages(&self) -> &AgeTable {
+        &self.ages
+    }
+
+    pub fn into_ages(self) -> AgeTable {
+        self.ages
+    }
+
+    /// The number of bytes promoted into the old generation
+    pub fn promoted(&self) -> usize {
+        self.promoted
+    }
+
+    /// The objects which were promoted into the old generation
+    pub fn promoted_objects(&self) -> &[DirectObjUnknown] {
+        &self.promoted_objects
+    }
+
+    /// Take the original location of every object which has been copied. The data of these
+    /// objects is now owned by their copies.
+    #[cfg(feature = "drop_heap")]
+    pub fn take_evacuated(&mut self) -> HashSet<DirectObjUnknown> {
+        std::mem::take(&mut self.evacuated)
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn threshold_follows_survivor_occupancy() {
+    let mut ages = AgeTable::default();
+    ages.add(1, 300);
+    ages.add(2, 300);
+    ages.add(3, 300);
+
+    // Ages 1 and 2 fit within the desired size, so objects are promoted once they reach age 3
+    assert_eq!(ages.compute_threshold(700, MAX_AGE), 3);
+    assert_eq!(ages.compute_threshold(200, MAX_AGE), 1);
+    assert_eq!(ages.compute_threshold(usize::MAX, 6), 6);
+    assert_eq!(ages.compute_threshold(usize::MAX, MAX_AGE), MAX_AGE);
+}
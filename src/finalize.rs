@@ -0,0 +1,405 @@
+//! Finalizers which run once an object has been found to be unreachable.
+//!
+//! Objects registered for finalization are not freed by the major collection which finds them to
+//! be unreachable. Instead they are resurrected along with everything they refer to and moved onto
+//! a queue, where they stay alive until their finalizer has been run by `run_finalizers` or by a
+//! finalizer thread. Once finalized, an object is collected like any other object the next time it
+//! is found to be unreachable. Minor collections treat every registered object as a root, so only
+//! major collections find objects to finalize.
+
+use crate::alloc::VirtualMachine;
+use crate::ptr::DirectObjUnknown;
+use crate::trace::Trace;
+use parking_lot::{Condvar, Mutex};
+use std::collections::{HashSet, VecDeque};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Cleanup which runs once for an object after it has become unreachable. Finalizers run while
+/// their object is mutably borrowed so it can not move, which means they must not block or wait
+/// on other threads. Allocations made by a finalizer fail once they would need a collection, as
+/// they do while borrowing. Weak pointers to an object are only cleared once it has been
+/// finalized and collected.
+pub trait Finalize {
+    fn finalize(&mut self);
+}
+
+/// The slots of objects registered for finalization and of the objects waiting to be finalized
+#[derive(Debug, Default)]
+pub(crate) struct FinalizationQueue {
+    registered: Mutex<HashSet<NonNull<DirectObjUnknown>>>,
+    pending: Mutex<VecDeque<NonNull<DirectObjUnknown>>>,
+    ready: Condvar,
+}
+
+/// The slots are only read by the collector or by a thread holding its counter
+unsafe impl Send for FinalizationQueue {}
+unsafe impl Sync for FinalizationQueue {}
+
+impl FinalizationQueue {
+    pub(crate) fn register(&self, slot: NonNull<DirectObjUnknown>) {
+        self.registered.lock().insert(slot);
+    }
+
+    /// The slots of every registered object which has not yet been found to be unreachable
+    pub(crate) fn registered_slots(&self) -> Vec<NonNull<DirectObjUnknown>> {
+        self.registered.lock().iter().copied().collect()
+    }
+
+    /// The slots of the objects waiting for their finalizers to run. These are treated as roots.
+    pub(crate) fn pending_slots(&self) -> Vec<NonNull<DirectObjUnknown>> {
+        self.pending.lock().iter().copied().collect()
+    }
+
+    /// Move every registered object whose slot is dead onto the queue and wake the finalizer
+    /// thread. Returns the slots of the objects which need to be resurrected.
+    pub(crate) fn enqueue_unreachable<F>(&self, is_dead: F) -> Vec<NonNull<DirectObjUnknown>>
+    where
+        F: Fn(NonNull<DirectObjUnknown>) -> bool,
+    {
+        let mut registered = self.registered.lock();
+        let unreachable = registered
+            .iter()
+            .copied()
+            .filter(|slot| is_dead(*slot))
+            .collect::<Vec<_>>();
+
+        if unreachable.is_empty() {
+            return unreachable;
+        }
+
+        for slot in &unreachable {
+            registered.remove(slot);
+        }
+
+        self.pending.lock().extend(unreachable.iter().copied());
+        self.ready.notify_all();
+        unreachable
+    }
+
+    pub(crate) fn pop(&self) -> Option<NonNull<DirectObjUnknown>> {
+        self.pending.lock().pop_front()
+    }
+
+    /// Return a slot taken by `pop` to the front of the queue
+    pub(crate) fn requeue(&self, slot: NonNull<DirectObjUnknown>) {
+        self.pending.lock().push_front(slot);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    /// Park until objects are waiting to be finalized. Returns false once `stop` is set.
+    fn wait(&self, stop: &AtomicBool) -> bool {
+        let mut pending = self.pending.lock();
+
+        loop {
+            if stop.load(Ordering::SeqCst) {
+                return false;
+            }
+
+            if !pending.is_empty() {
+                return true;
+            }
+
+            self.ready.wait(&mut pending);
+        }
+    }
+
+    /// Wake every thread waiting on the queue so it can check whether it has been stopped
+    fn wake(&self) {
+        // Taking the lock ensures a waiting thread is either parked or will see the stop flag
+        let _pending = self.pending.lock();
+        self.ready.notify_all();
+    }
+}
+
+/// A thread which runs finalizers as objects are queued by the collector. The thread is stopped
+/// and joined once this handle is dropped.
+pub struct FinalizerThread<T: Trace + Finalize + Send + 'static> {
+    vm: Arc<VirtualMachine<T>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl<T: Trace + Finalize + Send + 'static> FinalizerThread<T> {
+    pub(crate) fn spawn(vm: &Arc<VirtualMachine<T>>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = thread::Builder::new()
+            .name("gc-finalizer".into())
+            .spawn({
+                let vm = Arc::clone(vm);
+                let stop = Arc::clone(&stop);
+                move || {
+                    let allocator = vm.attach_thread();
+                    while vm.finalization().wait(&stop) {
+                        allocator.run_finalizers();
+                    }
+                }
+            })
+            .expect("failed to spawn finalizer thread");
+
+        FinalizerThread {
+            vm: Arc::clone(vm),
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop the thread once it has finished its current batch of finalizers. Equivalent to
+    /// dropping the handle.
+    pub fn stop(self) {}
+}
+
+impl<T: Trace + Finalize + Send + 'static> Drop for FinalizerThread<T> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.vm.finalization().wake();
+
+        if let Some(handle) = self.handle.take() {
+            // A panic in a finalizer has already been reported by the thread
+            let _ = handle.join();
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn unreachable_objects_are_resurrected_until_finalized() {
+    use crate::ptr::GcCell;
+    use crate::trace::TraceContext;
+    use crate::weak::WeakGcPtr;
+
+    struct Resource {
+        id: u64,
+        child: Option<GcCell<Resource>>,
+        finalized: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Trace for Resource {
+        unsafe fn trace(&self, cxt: &mut TraceContext) {
+            if let Some(child) = &self.child {
+                child.trace(cxt);
+            }
+        }
+    }
+
+    impl Finalize for Resource {
+        fn finalize(&mut self) {
+            // Everything the object refers to is resurrected along with it
+            let child = self
+                .child
+                .as_ref()
+                .map(|child| unsafe { (*child.get().direct_ptr()).id });
+            self.finalized
+                .lock()
+                .extend(child.into_iter().chain([self.id]));
+        }
+    }
+
+    let vm = VirtualMachine::<Resource>::test_builder().build();
+    let allocator = vm.attach_thread();
+    let finalized = Arc::new(Mutex::new(Vec::new()));
+    let resource = |id, child| Resource {
+        id,
+        child,
+        finalized: finalized.clone(),
+    };
+
+    let (parent, child) = {
+        let scope = allocator.handle_scope();
+        let child = scope.allocate(resource(2, None)).unwrap();
+        let parent = scope
+            .allocate(resource(1, Some(GcCell::new(child))))
+            .unwrap();
+        allocator.register_finalizer(parent);
+        (
+            WeakGcPtr::new(&allocator, parent),
+            WeakGcPtr::new(&allocator, child),
+        )
+    };
+
+    vm.collect_minor();
+    assert_eq!(vm.pending_finalizers(), 0);
+
+    vm.collect_major(false);
+    assert_eq!(vm.pending_finalizers(), 1);
+    vm.collect_major(true);
+    assert!(!child.is_cleared());
+
+    // Finalizers wait until nothing else is borrowing their object
+    {
+        let scope = allocator.handle_scope();
+        let root = parent.upgrade(&scope).unwrap();
+        let _borrow = allocator.borrow_mut(&root);
+        assert_eq!(allocator.run_finalizers(), 0);
+        assert_eq!(vm.pending_finalizers(), 1);
+    }
+
+    assert_eq!(allocator.run_finalizers(), 1);
+    assert_eq!(*finalized.lock(), [2, 1]);
+
+    // Finalizers only run once, after which the objects are collected as usual
+    vm.collect_major(false);
+    assert!(parent.is_cleared() && child.is_cleared());
+    assert_eq!(allocator.run_finalizers(), 0);
+    assert_eq!(finalized.lock().len(), 2);
+}
+
+#[test]
+#[cfg(test)]
+fn finalizer_thread_runs_queued_finalizers() {
+    use crate::trace::TraceContext;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Trace for Counted {
+        const IS_LEAF: bool = true;
+
+        unsafe fn trace(&self, _: &mut TraceContext) {}
+    }
+
+    impl Finalize for Counted {
+        fn finalize(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let vm = Arc::new(VirtualMachine::<Counted>::test_builder().build());
+    let finalizer = vm.start_finalizer_thread();
+    let count = Arc::new(AtomicUsize::new(0));
+
+    {
+        let allocator = vm.attach_thread();
+        let scope = allocator.handle_scope();
+        for _ in 0..3 {
+            let root = scope.allocate(Counted(count.clone())).unwrap();
+            allocator.register_finalizer(root);
+        }
+    }
+
+    vm.collect_major(false);
+    let start = Instant::now();
+    while count.load(Ordering::SeqCst) < 3 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    finalizer.stop();
+    assert_eq!(vm.pending_finalizers(), 0);
+    assert_eq!(vm.attached_threads().len(), 0);
+}
+
+#[test]
+#[cfg(test)]
+fn finalizers_fail_allocations_instead_of_collecting() {
+    use crate::alloc::ThreadAllocator;
+    use crate::trace::TraceContext;
+
+    struct Allocating {
+        /// The allocator running the finalizers
+        allocator: usize,
+        failed: Arc<AtomicBool>,
+    }
+
+    impl Trace for Allocating {
+        const IS_LEAF: bool = true;
+
+        unsafe fn trace(&self, _: &mut TraceContext) {}
+    }
+
+    impl Finalize for Allocating {
+        fn finalize(&mut self) {
+            let allocator = unsafe { &*(self.allocator as *const ThreadAllocator<Allocating>) };
+            let failed = self.failed.clone();
+
+            // Fill the nursery until allocating would need a collection
+            while allocator
+                .allocate(Allocating {
+                    allocator: 0,
+                    failed: failed.clone(),
+                })
+                .is_ok()
+            {}
+            self.failed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let vm = VirtualMachine::<Allocating>::test_builder().build();
+    let allocator = vm.attach_thread();
+    let failed = Arc::new(AtomicBool::new(false));
+
+    {
+        let scope = allocator.handle_scope();
+        let root = scope
+            .allocate(Allocating {
+                allocator: &allocator as *const _ as usize,
+                failed: failed.clone(),
+            })
+            .unwrap();
+        allocator.register_finalizer(root);
+    }
+
+    vm.collect_major(false);
+    assert_eq!(allocator.run_finalizers(), 1);
+    assert!(failed.load(Ordering::SeqCst));
+
+    // Allocations collect as usual once the finalizers have finished
+    let value = Allocating {
+        allocator: 0,
+        failed,
+    };
+    assert!(allocator.allocate(value).is_ok());
+}
+
+#[test]
+#[cfg(all(test, feature = "drop_heap"))]
+fn dead_objects_are_dropped_by_sweeps() {
+    use crate::mem::nursery::TenuringPolicy;
+    use crate::trace::TraceContext;
+    use std::sync::atomic::AtomicUsize;
+
+    struct Dropped(Arc<AtomicUsize>);
+
+    impl Trace for Dropped {
+        const IS_LEAF: bool = true;
+
+        unsafe fn trace(&self, _: &mut TraceContext) {}
+    }
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let vm = VirtualMachine::<Dropped>::test_builder()
+        .tenuring(TenuringPolicy::Fixed(0))
+        .build();
+    let allocator = vm.attach_thread();
+    let dropped = Arc::new(AtomicUsize::new(0));
+
+    // Objects left behind in the nursery are dropped, while their copies are not
+    {
+        let scope = allocator.handle_scope();
+        scope.allocate(Dropped(dropped.clone())).unwrap();
+        let _live = allocator.allocate(Dropped(dropped.clone())).unwrap();
+    }
+    vm.collect_minor();
+    assert_eq!(dropped.load(Ordering::SeqCst), 1);
+
+    {
+        let scope = allocator.handle_scope();
+        scope.allocate(Dropped(dropped.clone())).unwrap();
+        vm.collect_minor();
+    }
+    assert_eq!(dropped.load(Ordering::SeqCst), 1);
+    vm.collect_major(false);
+    assert_eq!(dropped.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,178 @@
+//! Scoped roots for references held on the stack, in the style of V8's `HandleScope`.
+//!
+//! Every `GcPtr` owns a slot in the reference table and every slot is treated as a root until it
+//! is unrooted, so a pointer which is dropped without its slot being unrooted keeps its object
+//! alive forever. Objects allocated through a `HandleScope` have their slots unrooted in bulk when
+//! the scope ends, and the collector frees each slot once its object can no longer be reached
+//! from the heap. A handle which needs to stay a root past the end of its scope must be escaped.
+//! Upgrading a weak pointer roots its object within a scope in the same way, and a slot stays a
+//! root until every root held on it has been released.
+
+use crate::alloc::{AllocError, ThreadAllocator};
+use crate::ptr::{DirectObjUnknown, GcPtr, Handle, Sealed};
+use crate::trace::Trace;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+
+/// Owns the slots of every object allocated within it and unroots them once dropped
+pub struct HandleScope<'a, 'heap, T: Trace> {
+    allocator: &'a ThreadAllocator<'heap, T>,
+    slots: RefCell<Vec<GcPtr<T>>>,
+}
+
+/// A reference to an object which is kept alive until the end of its scope. Roots are used in
+/// place of a `GcPtr` wherever the VM accepts a `Handle`, and the only way to turn one back into a
+/// `GcPtr` is to escape it from its scope.
+pub struct Root<'scope, T> {
+    ptr: GcPtr<T>,
+    _scope: PhantomData<&'scope ()>,
+}
+
+impl<'scope, T> Clone for Root<'scope, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'scope, T> Copy for Root<'scope, T> {}
+
+impl<'scope, T> Root<'scope, T> {
+    /// Get the direct pointer to this object in memory. This pointer may shift during garbage
+    /// collection.
+    pub fn direct_ptr(&self) -> *mut T {
+        // Safety: The slot stays a root until the scope ends, so it can not have been freed
+        unsafe { self.ptr.direct_ptr() }
+    }
+}
+
+impl<'scope, T> Handle<T> for Root<'scope, T> {
+    fn gc_ptr(&self, _: Sealed) -> GcPtr<T> {
+        self.ptr
+    }
+}
+
+impl<'a, 'heap, T: Trace> HandleScope<'a, 'heap, T> {
+    pub fn new(allocator: &'a ThreadAllocator<'heap, T>) -> Self {
+        HandleScope {
+            allocator,
+            slots: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Allocate an object which stays alive until this scope ends
+    pub fn allocate(&self, value: T) -> Result<Root<'_, T>, AllocError> {
+        let ptr = self.allocator.allocate(value)?;
+        self.slots.borrow_mut().push(ptr);
+
+        Ok(Root {
+            ptr,
+            _scope: PhantomData,
+        })
+    }
+
+    /// Take ownership of a pointer's slot so it is unrooted when this scope ends. Used to move an
+    /// escaped handle into an outer scope.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by the same VM and must still be a root which is not owned
+    /// by another scope.
+    pub unsafe fn adopt(&self, ptr: GcPtr<T>) -> Root<'_, T> {
+        self.slots.borrow_mut().push(ptr);
+
+        Root {
+            ptr,
+            _scope: PhantomData,
+        }
+    }
+
+    /// Root the object in the slot returned by `find` until this scope ends, or return None if no
+    /// slot was found. Used to upgrade weak pointers and ephemerons, whose pointers are typed
+    /// separately from the VM.
+    ///
+    /// # Panics
+    /// Panics if the slot does not belong to this scope's VM.
+    pub(crate) fn root<U, F>(&self, find: F) -> Option<Root<'_, U>>
+    where
+        F: FnOnce() -> Option<NonNull<DirectObjUnknown>>,
+    {
+        let ptr = self.allocator.root_slot(find)?;
+        self.slots.borrow_mut().push(ptr);
+
+        Some(Root {
+            // Safety: The slot was just rooted, so it is still assigned to its object
+            ptr: unsafe { GcPtr::from_slot(ptr.slot().cast()) },
+            _scope: PhantomData,
+        })
+    }
+
+    /// Keep a handle alive past the end of this scope. The returned pointer holds on to its root
+    /// until it is adopted by another scope.
+    pub fn escape(&self, root: Root<'_, T>) -> GcPtr<T> {
+        let mut slots = self.slots.borrow_mut();
+
+        // Handles are usually escaped shortly after they are created
+        let idx = slots
+            .iter()
+            .rposition(|ptr| ptr.slot() == root.ptr.slot())
+            .expect("handle does not belong to this scope");
+        slots.swap_remove(idx);
+
+        root.ptr
+    }
+
+    /// The number of handles owned by this scope
+    pub fn len(&self) -> usize {
+        self.slots.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.borrow().is_empty()
+    }
+}
+
+impl<'a, 'heap, T: Trace> Drop for HandleScope<'a, 'heap, T> {
+    fn drop(&mut self) {
+        let slots = self.slots.get_mut().drain(..).map(|ptr| ptr.slot().cast());
+
+        // Safety: Every slot was claimed for a handle in this scope, and the handles can not
+        // outlive it.
+        unsafe { self.allocator.unroot_slots(slots) };
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn scopes_release_slots_unless_escaped() {
+    use crate::alloc::VirtualMachine;
+
+    let vm = VirtualMachine::<u64>::test_builder().build();
+    let allocator = vm.attach_thread();
+    let live_slots = || vm.live_slot_count();
+
+    let escaped = {
+        let outer = allocator.handle_scope();
+
+        let escaped = {
+            let inner = allocator.handle_scope();
+            let roots = (0..4)
+                .map(|x| inner.allocate(x).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(live_slots(), 4);
+
+            let escaped = inner.escape(roots[2]);
+            unsafe { outer.adopt(escaped) }
+        };
+
+        // The slots of the other handles are freed once the collector finds their objects dead
+        assert_eq!(live_slots(), 4);
+        vm.collect_minor();
+        assert_eq!(live_slots(), 1);
+        assert_eq!(unsafe { *escaped.direct_ptr() }, 2);
+        outer.escape(escaped)
+    };
+
+    vm.collect_minor();
+    assert_eq!(live_slots(), 1);
+    assert_eq!(unsafe { *escaped.direct_ptr() }, 2);
+}
@@ -1,12 +1,20 @@
 #![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 
+extern crate self as generational_gc;
+
 pub mod alloc;
+pub mod borrow;
 pub mod collect;
+pub mod finalize;
+pub mod handle;
 pub mod header;
 pub mod mark;
 pub mod mem;
 pub mod monitor;
 pub mod ptr;
 pub mod ref_table;
+pub mod reference;
+mod sync;
 pub mod trace;
 pub mod util;
+pub mod weak;
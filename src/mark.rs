@@ -1,6 +1,5 @@
 use bitflags::bitflags;
 use std::hint::spin_loop;
-use std::ptr::NonNull;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 pub trait MarkWord: Default {
@@ -12,11 +11,21 @@ pub trait MarkWord: Default {
 
     /// Remove mark
     fn unmark(&self);
+
+    /// The number of minor collections this object has survived
+    fn age(&self) -> usize;
+
+    /// Increase the age of this object by one, saturating at `MAX_AGE`
+    fn increment_age(&self);
 }
 
+/// The oldest age which can be recorded in the age bits of a mark word
+pub const MAX_AGE: usize = HotspotMarkBits::AGE.bits >> HotspotMarkBits::AGE_SHIFT;
+
 bitflags! {
     struct TestMarkBits: u64 {
         const MARK_BIT = 0x0000_0000_0000_0001;
+        const AGE = HotspotMarkBits::AGE.bits as u64;
     }
 }
 
@@ -50,7 +59,23 @@ impl MarkWord for TestMark {
         self.mark
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bits| {
                 Some(bits & !TestMarkBits::MARK_BIT.bits)
-            });
+            })
+            .unwrap();
+    }
+
+    fn age(&self) -> usize {
+        let bits = self.mark.load(Ordering::SeqCst);
+        HotspotMarkBits::from_bits_truncate(bits as usize).age()
+    }
+
+    fn increment_age(&self) {
+        self.mark
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bits| {
+                let mark = HotspotMarkBits::from_bits_truncate(bits as usize);
+                let aged = mark.with_age(mark.age().saturating_add(1).min(MAX_AGE));
+                Some((bits & !TestMarkBits::AGE.bits) | aged.bits as u64)
+            })
+            .unwrap();
     }
 }
 
@@ -93,8 +118,21 @@ const INFLATING: usize = 0;
 // }
 
 impl HotspotMarkBits {
+    const AGE_SHIFT: u32 = Self::AGE.bits.trailing_zeros();
+
     // pub fn state(self) -> MarkState {}
 
+    /// The number of minor collections survived by the object
+    pub fn age(self) -> usize {
+        (self.bits & Self::AGE.bits) >> Self::AGE_SHIFT
+    }
+
+    /// Replace the age bits. Ages which do not fit within the age bits are truncated.
+    pub fn with_age(self, age: usize) -> Self {
+        let age = (age << Self::AGE_SHIFT) & Self::AGE.bits;
+        Self::from_bits_truncate((self.bits & !Self::AGE.bits) | age)
+    }
+
     pub fn inflating(self) -> bool {
         self == Self::INFLATING
     }
@@ -108,7 +146,7 @@ impl HotspotMarkBits {
     }
 
     pub const fn min_alignment() -> usize {
-        let usage = (Self::LOCK.bits | Self::AGE.bits);
+        let usage = Self::LOCK.bits | Self::AGE.bits;
         usage.next_power_of_two()
     }
 }
@@ -7,9 +7,18 @@ use std::alloc::{AllocError, Allocator, Global};
 #[cfg(not(feature = "allocator_api"))]
 use std::alloc::{GlobalAlloc, System};
 
+/// A contiguous block of memory which objects can be allocated within.
+///
+/// # Safety
+/// The memory from `start` to `start + len` must be valid for reads and writes for as long as the
+/// block is alive.
 pub unsafe trait AllocationBlock {
     fn start(&self) -> NonNull<u8>;
     fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 pub struct OwnedMemoryBlock<#[cfg(feature = "allocator_api")] A: Allocator = Global> {
@@ -19,6 +28,9 @@ pub struct OwnedMemoryBlock<#[cfg(feature = "allocator_api")] A: Allocator = Glo
     allocator: A,
 }
 
+/// The block exclusively owns the memory it points to
+unsafe impl Send for OwnedMemoryBlock {}
+
 unsafe impl AllocationBlock for OwnedMemoryBlock {
     fn start(&self) -> NonNull<u8> {
         self.ptr
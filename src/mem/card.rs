@@ -0,0 +1,234 @@
+use std::cell::UnsafeCell;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Each card covers 2^CARD_SHIFT bytes of memory
+pub const CARD_SHIFT: u32 = 9;
+pub const CARD_SIZE: usize = 1 << CARD_SHIFT;
+
+/// Tracks which parts of a region have been written to since the last minor collection. A card is
+/// dirtied by the write barrier whenever a reference which may point into the young generation is
+/// stored within it, so a minor collection only needs to scan dirty cards to find old objects
+/// which refer to young objects.
+pub struct CardTable {
+    range: Range<usize>,
+    cards: Box<[AtomicBool]>,
+}
+
+impl CardTable {
+    /// Create a table where every card covering `range` is clean
+    pub fn new(range: Range<usize>) -> Self {
+        let len = (range.end - range.start + CARD_SIZE - 1) >> CARD_SHIFT;
+
+        CardTable {
+            range,
+            cards: (0..len).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    /// The addresses covered by this table
+    pub fn covered(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    fn index_of(&self, addr: usize) -> Option<usize> {
+        self.range
+            .contains(&addr)
+            .then(|| (addr - self.range.start) >> CARD_SHIFT)
+    }
+
+    /// Dirty the card holding the given address. Returns false if the address is not covered by
+    /// this table.
+    pub fn dirty(&self, addr: usize) -> bool {
+        match self.index_of(addr) {
+            Some(idx) => {
+                self.cards[idx].store(true, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Dirty every card overlapping `range`. Cards outside of this table are ignored.
+    pub fn dirty_range(&self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let first = self.index_of(range.start.max(self.range.start));
+        let last = self.index_of((range.end - 1).min(self.range.end - 1));
+        if let (Some(first), Some(last)) = (first, last) {
+            for card in &self.cards[first..=last] {
+                card.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    pub fn is_dirty(&self, addr: usize) -> bool {
+        self.index_of(addr)
+            .is_some_and(|idx| self.cards[idx].load(Ordering::Acquire))
+    }
+
+    /// Clean the card holding the given address
+    pub fn clean(&self, addr: usize) {
+        if let Some(idx) = self.index_of(addr) {
+            self.cards[idx].store(false, Ordering::Release);
+        }
+    }
+
+    /// The address ranges covered by every dirty card
+    pub fn dirty_cards(&self) -> Vec<Range<usize>> {
+        self.cards
+            .iter()
+            .enumerate()
+            .filter(|(_, card)| card.load(Ordering::Acquire))
+            .map(|(idx, _)| {
+                let start = self.range.start + (idx << CARD_SHIFT);
+                start..(start + CARD_SIZE).min(self.range.end)
+            })
+            .collect()
+    }
+
+    /// Clean every card in this table
+    pub fn clear(&self) {
+        for card in self.cards.iter() {
+            card.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// The card tables of every region in the old generation, sorted by address so the card holding
+/// any address can be found with a binary search. Tables are only added or removed while no other
+/// thread is looking up cards, which lets the write barrier run without taking a lock.
+#[derive(Default)]
+pub struct CardTables {
+    tables: UnsafeCell<Vec<CardTable>>,
+}
+
+/// The tables are only changed through `insert` and `remove`, whose callers guarantee that no
+/// other thread is accessing the set. Cards themselves are atomic.
+unsafe impl Send for CardTables {}
+unsafe impl Sync for CardTables {}
+
+impl CardTables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn tables(&self) -> &[CardTable] {
+        // Safety: The tables are only changed while nothing else is accessing them
+        unsafe { &*self.tables.get() }
+    }
+
+    /// The table covering an address
+    pub fn find(&self, addr: usize) -> Option<&CardTable> {
+        let tables = self.tables();
+        let idx = tables.partition_point(|table| table.range.start <= addr);
+
+        idx.checked_sub(1)
+            .map(|idx| &tables[idx])
+            .filter(|table| table.range.contains(&addr))
+    }
+
+    /// Check if an address is covered by any of the tables
+    pub fn covers(&self, addr: usize) -> bool {
+        self.find(addr).is_some()
+    }
+
+    /// Dirty the card holding an address. Returns false if the address is not covered.
+    pub fn dirty(&self, addr: usize) -> bool {
+        self.find(addr).is_some_and(|table| table.dirty(addr))
+    }
+
+    /// Dirty every card holding part of `range`, which must not span more than one table
+    pub fn dirty_range(&self, range: Range<usize>) {
+        if let Some(table) = self.find(range.start) {
+            table.dirty_range(range);
+        }
+    }
+
+    pub fn is_dirty(&self, addr: usize) -> bool {
+        self.find(addr).is_some_and(|table| table.is_dirty(addr))
+    }
+
+    /// The write barrier. Dirties the card holding `field` unless `value` is also covered, since
+    /// only references leaving the covered memory need to be remembered.
+    pub fn write_barrier(&self, field: usize, value: usize) {
+        if !self.covers(value) {
+            self.dirty(field);
+        }
+    }
+
+    /// Clean every card in every table
+    pub fn clear(&self) {
+        self.tables().iter().for_each(CardTable::clear);
+    }
+
+    /// Add a table, keeping the tables sorted by address
+    ///
+    /// # Safety
+    /// No other thread may be accessing this set, and no references to its tables may be held.
+    pub unsafe fn insert(&self, table: CardTable) {
+        let tables = &mut *self.tables.get();
+        let idx = tables.partition_point(|existing| existing.range.start < table.range.start);
+        tables.insert(idx, table);
+    }
+
+    /// Remove the table covering an address
+    ///
+    /// # Safety
+    /// No other thread may be accessing this set, and no references to its tables may be held.
+    pub unsafe fn remove(&self, addr: usize) {
+        let tables = &mut *self.tables.get();
+        tables.retain(|table| !table.range.contains(&addr));
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn card_tables_are_found_by_address() {
+    let tables = CardTables::new();
+    unsafe {
+        tables.insert(CardTable::new(8192..8192 + 1000));
+        tables.insert(CardTable::new(4096..4096 + 2 * CARD_SIZE));
+    }
+
+    assert!(tables.covers(4096) && tables.covers(8192 + 999));
+    assert!(!tables.covers(8192 + 1000) && !tables.covers(4096 + 2 * CARD_SIZE));
+
+    // References between covered addresses do not need to be remembered
+    tables.write_barrier(4096 + CARD_SIZE, 8192);
+    assert!(!tables.is_dirty(4096 + CARD_SIZE));
+    tables.write_barrier(8192 + 600, 64);
+    assert!(tables.is_dirty(8192 + 600));
+    assert_eq!(
+        tables.find(8192).unwrap().dirty_cards(),
+        vec![8192 + CARD_SIZE..8192 + 1000]
+    );
+
+    unsafe { tables.remove(8192) };
+    assert!(!tables.is_dirty(8192 + 600));
+}
+
+#[test]
+#[cfg(test)]
+fn cards_cover_their_range() {
+    let table = CardTable::new(4096..4096 + 4 * CARD_SIZE);
+
+    assert!(table.dirty(4096 + CARD_SIZE + 8));
+    assert!(!table.dirty(4096 + 4 * CARD_SIZE));
+    assert!(!table.dirty(4095));
+
+    assert_eq!(
+        table.dirty_cards(),
+        vec![4096 + CARD_SIZE..4096 + 2 * CARD_SIZE]
+    );
+
+    // Every card overlapping a range is dirtied, ignoring the part outside of the table
+    table.dirty_range(4096 - 8..4096 + 2 * CARD_SIZE + 8);
+    assert_eq!(table.dirty_cards().len(), 3);
+    assert!(!table.is_dirty(4096 + 3 * CARD_SIZE));
+
+    table.clear();
+    assert!(table.dirty_cards().is_empty());
+}
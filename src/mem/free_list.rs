@@ -0,0 +1,154 @@
+use std::ptr::NonNull;
+
+/// A gap left behind by the sweep which can be reused for new objects
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FreeChunk {
+    /// The index of the region holding this chunk
+    pub region: usize,
+    pub start: NonNull<u8>,
+    pub size: usize,
+}
+
+/// A chunk only refers to memory owned by the heap which tracks it
+unsafe impl Send for FreeChunk {}
+
+/// Free chunks segregated by size. Each class holds chunks with sizes from `2^n` up to but not
+/// including `2^(n + 1)` bytes, so a request can skip straight to the classes which are able to
+/// satisfy it.
+#[derive(Debug, Default)]
+pub struct FreeLists {
+    classes: Vec<Vec<FreeChunk>>,
+    free: usize,
+    chunks: usize,
+}
+
+/// Statistics describing how fragmented the free space of a heap is
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FragmentationStats {
+    /// The total number of bytes held by free chunks
+    pub free_bytes: usize,
+    /// The number of free chunks
+    pub free_chunks: usize,
+    /// The size of the largest free chunk
+    pub largest_chunk: usize,
+    /// The fraction of free memory which is not part of the largest chunk. A value of 0 means all
+    /// free memory is contiguous.
+    pub fragmentation: f64,
+}
+
+impl FreeLists {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn class_of(size: usize) -> usize {
+        (usize::BITS - 1 - size.leading_zeros()) as usize
+    }
+
+    /// The total number of bytes held by free chunks
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks == 0
+    }
+
+    pub fn insert(&mut self, chunk: FreeChunk) {
+        let class = Self::class_of(chunk.size);
+        if self.classes.len() <= class {
+            self.classes.resize_with(class + 1, Vec::new);
+        }
+
+        self.classes[class].push(chunk);
+        self.free += chunk.size;
+        self.chunks += 1;
+    }
+
+    /// Remove the first chunk which can hold `size` bytes. To keep the heap walkable, a chunk is
+    /// only chosen if it would leave nothing or at least `min_remainder` bytes behind.
+    pub fn take(&mut self, size: usize, min_remainder: usize) -> Option<FreeChunk> {
+        let fits = |chunk: &FreeChunk| {
+            chunk.size == size || chunk.size >= size.saturating_add(min_remainder)
+        };
+
+        for class in Self::class_of(size.max(1))..self.classes.len() {
+            if let Some(idx) = self.classes[class].iter().position(fits) {
+                let chunk = self.classes[class].swap_remove(idx);
+                self.free -= chunk.size;
+                self.chunks -= 1;
+                return Some(chunk);
+            }
+        }
+
+        None
+    }
+
+    /// The number of bytes which are certain to be reusable if up to `waste` bytes at the end of
+    /// every chunk are too small to hold the next allocation
+    pub fn usable_bytes(&self, waste: usize) -> usize {
+        self.classes
+            .iter()
+            .flatten()
+            .map(|chunk| chunk.size.saturating_sub(waste))
+            .sum()
+    }
+
+    /// Forget every free chunk
+    pub fn clear(&mut self) {
+        self.classes.clear();
+        self.free = 0;
+        self.chunks = 0;
+    }
+
+    pub fn stats(&self) -> FragmentationStats {
+        let largest_chunk = self
+            .classes
+            .iter()
+            .flatten()
+            .map(|chunk| chunk.size)
+            .max()
+            .unwrap_or(0);
+
+        let fragmentation = match self.free {
+            0 => 0.0,
+            free => 1.0 - largest_chunk as f64 / free as f64,
+        };
+
+        FragmentationStats {
+            free_bytes: self.free,
+            free_chunks: self.chunks,
+            largest_chunk,
+            fragmentation,
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn chunks_are_taken_by_size() {
+    let memory = [0u64; 64];
+    let chunk = |offset: usize, size: usize| FreeChunk {
+        region: 0,
+        start: NonNull::from(&memory[offset]).cast(),
+        size,
+    };
+
+    let mut lists = FreeLists::new();
+    lists.insert(chunk(0, 48));
+    lists.insert(chunk(8, 100));
+    lists.insert(chunk(32, 256));
+
+    assert_eq!(lists.stats().largest_chunk, 256);
+    assert_eq!(lists.free_bytes(), 404);
+
+    // 48 bytes would leave a remainder which is too small to be walked over
+    assert_eq!(lists.take(40, 32).map(|chunk| chunk.size), Some(100));
+    assert_eq!(lists.take(48, 32).map(|chunk| chunk.size), Some(48));
+    assert_eq!(lists.take(512, 32), None);
+    assert_eq!(lists.len(), 1);
+}
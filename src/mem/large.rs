@@ -0,0 +1,186 @@
+use crate::collect::VisitHeap;
+use crate::mark::MarkWord;
+use crate::mem::block::{AllocationBlock, OwnedMemoryBlock};
+use crate::mem::{Heap, HeapBudget, HeapRegion};
+use crate::ptr::DirectObjUnknown;
+use crate::trace::{AnnotatedMixedHeap, HeapObjectLayout, HeapObjectSetup};
+use std::alloc::Layout;
+use std::iter::Map;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr::NonNull;
+use std::slice::Iter;
+use std::sync::Arc;
+
+/// Check if an object with the given (wrapped) layout is too large or too strictly aligned to be
+/// placed within a regular heap region.
+pub fn is_large_object(layout: Layout, threshold: usize) -> bool {
+    layout.size() >= threshold || layout.align() > HeapRegion::<(), ()>::heap_align()
+}
+
+/// A single object which has been given its own block of memory
+pub struct LargeObject {
+    block: OwnedMemoryBlock,
+    object: DirectObjUnknown,
+}
+
+/// The object always lives within the block which owns it
+unsafe impl Send for LargeObject {}
+
+/// Space for objects which are too large or too strictly aligned to be placed within a TLAB. Every
+/// object receives its own block of memory so they never need to be copied and can be freed
+/// individually. The memory of every object is reserved from a budget shared with the old
+/// generation.
+pub struct LargeObjectSpace<L = AnnotatedMixedHeap> {
+    objects: Vec<LargeObject>,
+    threshold: usize,
+    budget: Arc<HeapBudget>,
+    allocated: usize,
+    _phantom: PhantomData<L>,
+}
+
+impl<L> LargeObjectSpace<L> {
+    /// Create a new large object space for objects of at least `threshold` bytes which reserves
+    /// the memory of its objects from `budget`.
+    pub fn new(threshold: usize, budget: Arc<HeapBudget>) -> Self {
+        LargeObjectSpace {
+            objects: Vec::new(),
+            threshold,
+            budget,
+            allocated: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// The number of bytes currently held by objects in this space
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// The number of objects currently held in this space
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Check if an object with the given (wrapped) layout belongs in this space rather than in a
+    /// regular heap region.
+    pub fn is_large(&self, layout: Layout) -> bool {
+        is_large_object(layout, self.threshold)
+    }
+
+    /// Allocate memory for an object with the given (wrapped) layout
+    pub fn alloc_layout(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if !self.budget.try_reserve(layout.size()) {
+            return None;
+        }
+
+        let block = OwnedMemoryBlock::new(layout);
+        let start = block.start();
+
+        self.allocated += block.len();
+        self.objects.push(LargeObject {
+            block,
+            object: start.cast(),
+        });
+
+        Some(start)
+    }
+}
+
+impl<L: HeapObjectLayout> LargeObjectSpace<L> {
+    pub fn alloc<T>(&mut self) -> Option<NonNull<T>>
+    where
+        L: HeapObjectSetup<T>,
+    {
+        let layout = L::wrap_layout(Layout::new::<T>());
+        let allocated = self.alloc_layout(layout)?;
+
+        let object = unsafe { L::init_object(allocated, layout) };
+        self.objects.last_mut().unwrap().object = object.cast();
+        Some(object)
+    }
+
+    /// Free every object which has not been marked and remove the mark from all remaining objects.
+    /// With the `drop_heap` feature, dead objects are dropped first. Returns the number of bytes
+    /// freed.
+    ///
+    /// # Safety
+    /// The mark phase must have completed so that all reachable objects in this space are marked.
+    pub unsafe fn sweep(&mut self) -> usize {
+        let mut freed = 0;
+
+        self.objects.retain(|large| {
+            let mark = L::mark(large.object);
+            if mark.is_marked() {
+                mark.unmark();
+                return true;
+            }
+
+            freed += large.block.len();
+            #[cfg(feature = "drop_heap")]
+            L::drop(large.object);
+            false
+        });
+
+        self.allocated -= freed;
+        self.budget.release(freed);
+        freed
+    }
+}
+
+unsafe impl<'a, L: HeapObjectLayout> VisitHeap for &'a LargeObjectSpace<L> {
+    type Layout = L;
+    #[allow(clippy::type_complexity)]
+    type EntryIter = Map<Iter<'a, LargeObject>, fn(&LargeObject) -> DirectObjUnknown>;
+
+    fn iter_entries(self) -> Self::EntryIter {
+        self.objects.iter().map(|large| large.object)
+    }
+}
+
+impl<T, L> Heap<T> for LargeObjectSpace<L>
+where
+    L: HeapObjectLayout + HeapObjectSetup<T>,
+{
+    fn try_alloc_uninit(&mut self) -> Option<NonNull<MaybeUninit<T>>> {
+        self.alloc().map(NonNull::cast)
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn over_aligned_objects_are_supported() {
+    use crate::trace::{Trace, TraceContext};
+
+    #[repr(align(64))]
+    struct Simd([f32; 16]);
+
+    impl Trace for Simd {
+        unsafe fn trace(&self, _: &mut TraceContext) {}
+    }
+
+    let budget = Arc::new(HeapBudget::new(1 << 20));
+    let mut space = LargeObjectSpace::<AnnotatedMixedHeap>::new(4096, budget.clone());
+    let layout = <AnnotatedMixedHeap as HeapObjectSetup<Simd>>::wrap_layout(Layout::new::<Simd>());
+    assert!(space.is_large(layout));
+
+    let ptr = space.try_push_to_heap(Simd([1.0; 16])).ok().unwrap();
+    assert_eq!(ptr.as_ptr() as usize % 64, 0);
+    assert_eq!(unsafe { ptr.as_ref().0 }, [1.0; 16]);
+
+    unsafe {
+        assert_eq!(AnnotatedMixedHeap::layout(ptr.cast()), layout);
+        assert_eq!(budget.reserved(), layout.size());
+        assert_eq!(space.sweep(), layout.size());
+    }
+    assert!(space.is_empty());
+    assert_eq!(budget.reserved(), 0);
+}
@@ -6,10 +6,17 @@ use std::alloc::Layout;
 use std::iter::Copied;
 use std::marker::PhantomData;
 use std::mem::{align_of, MaybeUninit};
+use std::ops::Range;
 use std::ptr::NonNull;
 use std::slice::Iter;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub mod block;
+pub mod card;
+pub mod free_list;
+pub mod large;
+pub mod nursery;
+pub mod old;
 
 pub trait Heap<T> {
     /// Returns a direct pointer to the uninitialized data if the allocation was successful.
@@ -22,13 +29,62 @@ pub trait Heap<T> {
         match self.try_alloc_uninit() {
             None => Err(value),
             Some(mut ptr) => unsafe {
-                *ptr.as_mut().as_mut_ptr() = value;
+                ptr.as_mut().as_mut_ptr().write(value);
                 Ok(ptr.cast())
             },
         }
     }
 }
 
+/// The number of bytes shared by the old generation and the large object space. Both spaces
+/// reserve memory from the same budget before allocating it, so together they never grow past the
+/// part of the heap which is not used by the nursery.
+#[derive(Debug)]
+pub struct HeapBudget {
+    capacity: usize,
+    reserved: AtomicUsize,
+}
+
+impl HeapBudget {
+    pub fn new(capacity: usize) -> Self {
+        HeapBudget {
+            capacity,
+            reserved: AtomicUsize::new(0),
+        }
+    }
+
+    /// The total number of bytes which may be reserved
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of bytes currently reserved
+    pub fn reserved(&self) -> usize {
+        self.reserved.load(Ordering::SeqCst)
+    }
+
+    /// The number of bytes which can still be reserved
+    pub fn available(&self) -> usize {
+        self.capacity - self.reserved()
+    }
+
+    /// Reserve space for an allocation. Returns false if the budget does not have enough room.
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        self.reserved
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reserved| {
+                reserved
+                    .checked_add(bytes)
+                    .filter(|total| *total <= self.capacity)
+            })
+            .is_ok()
+    }
+
+    /// Return space which was reserved for memory which has since been released
+    pub fn release(&self, bytes: usize) {
+        self.reserved.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
 pub struct HeapRegion<R, L = AnnotatedMixedHeap> {
     region: R,
     remaining: NonNull<u8>,
@@ -36,17 +92,14 @@ pub struct HeapRegion<R, L = AnnotatedMixedHeap> {
     _phantom: PhantomData<L>,
 }
 
+/// The pointers held by a region only ever refer to the memory it owns
+unsafe impl<R: Send, L> Send for HeapRegion<R, L> {}
+
 impl<R: AllocationBlock, L> From<R> for HeapRegion<R, L> {
     fn from(region: R) -> Self {
-        let mut remaining = region.start().as_ptr() as usize;
-
-        if remaining % Self::heap_align() > 0 {
-            remaining += Self::heap_align() - (remaining % Self::heap_align())
-        }
-
         HeapRegion {
+            remaining: Self::from_start(&region),
             region,
-            remaining: NonNull::new(remaining as _).unwrap(),
             objects: Vec::new(),
             _phantom: PhantomData,
         }
@@ -76,6 +129,50 @@ unsafe impl<'a, R, L: HeapObjectLayout> VisitHeap for &'a HeapRegion<R, L> {
 }
 
 impl<R: AllocationBlock, L> HeapRegion<R, L> {
+    /// The addresses covered by the block of memory backing this region
+    pub fn address_range(&self) -> Range<usize> {
+        let start = self.region.start().as_ptr() as usize;
+        start..start + self.region.len()
+    }
+
+    /// Check if an address falls within the block of memory backing this region
+    pub fn contains(&self, ptr: NonNull<()>) -> bool {
+        self.address_range().contains(&(ptr.as_ptr() as usize))
+    }
+
+    /// The number of bytes which have been allocated within this region
+    pub fn used_space(&self) -> usize {
+        self.region.len() - self.remaining_space()
+    }
+
+    /// Forget every object within this region so its memory can be reused. The objects are not
+    /// dropped.
+    pub fn reset(&mut self) {
+        self.objects.clear();
+        self.remaining = Self::from_start(&self.region);
+    }
+
+    /// The first address objects can be allocated at
+    pub fn start(&self) -> NonNull<u8> {
+        Self::from_start(&self.region)
+    }
+
+    /// The address just past the end of the block of memory backing this region
+    pub fn end(&self) -> usize {
+        self.address_range().end
+    }
+
+    /// The first aligned address within a block of memory
+    fn from_start(region: &R) -> NonNull<u8> {
+        let mut remaining = region.start().as_ptr() as usize;
+
+        if !remaining.is_multiple_of(Self::heap_align()) {
+            remaining += Self::heap_align() - (remaining % Self::heap_align())
+        }
+
+        NonNull::new(remaining as _).unwrap()
+    }
+
     pub fn remaining_space(&self) -> usize {
         self.region.len()
             - (self.remaining.as_ptr() as usize - self.region.start().as_ptr() as usize)
@@ -106,7 +203,87 @@ impl<R: AllocationBlock, L: HeapObjectLayout> HeapRegion<R, L> {
         let layout = L::wrap_layout(Layout::new::<T>());
         let allocated = self.alloc_layout(layout)?;
 
-        unsafe { Some(L::init_object(allocated, layout)) }
+        let object = unsafe { L::init_object(allocated, layout) };
+        self.objects.push(object.cast());
+        Some(object)
+    }
+
+    /// Walk every object in this region in order of address, using the layout stored with each
+    /// object to find the next. Fillers are skipped.
+    ///
+    /// # Safety
+    /// Every allocation in this region must hold an object or a filler.
+    pub unsafe fn walk(&self) -> impl Iterator<Item = DirectObjUnknown> + '_ {
+        self.walk_allocations()
+            .filter(|object| !L::is_filler(*object))
+    }
+
+    /// Walk every allocation in this region in order of address, including fillers
+    ///
+    /// # Safety
+    /// Every allocation in this region must hold an object or a filler.
+    pub(crate) unsafe fn walk_allocations(&self) -> impl Iterator<Item = DirectObjUnknown> + '_ {
+        let top = self.remaining.as_ptr() as usize;
+        let mut cursor = Self::from_start(&self.region).as_ptr() as usize;
+
+        std::iter::from_fn(move || {
+            if cursor >= top {
+                return None;
+            }
+
+            let object = L::object_at(NonNull::new_unchecked(cursor as *mut u8));
+            cursor += Self::allocation_size(L::layout(object));
+            Some(object)
+        })
+    }
+
+    /// The number of bytes used within a region by an allocation of the given layout
+    pub(crate) fn allocation_size(layout: Layout) -> usize {
+        let align = Self::heap_align();
+        (layout.size() + align - 1) & !(align - 1)
+    }
+
+    /// Every object which overlaps the given range of addresses, including its header
+    ///
+    /// # Safety
+    /// Every object in this region must still be live.
+    pub unsafe fn objects_overlapping(&self, range: Range<usize>) -> &[DirectObjUnknown] {
+        let end = self
+            .objects
+            .partition_point(|object| (object.as_ptr() as usize) < range.end);
+
+        let mut start = end;
+        while start > 0 {
+            let object = self.objects[start - 1];
+            let object_end =
+                object.as_ptr() as usize - L::data_offset(object) + L::layout(object).size();
+
+            if object_end <= range.start {
+                break;
+            }
+            start -= 1;
+        }
+
+        &self.objects[start..end]
+    }
+
+    /// Copy an object, including its header, into this region. Returns a pointer to the copy, or
+    /// None if there was not enough space remaining. The original is left untouched.
+    ///
+    /// # Safety
+    /// `object` must point to a live object which was allocated using the same layout as this
+    /// region.
+    pub unsafe fn copy_object(&mut self, object: DirectObjUnknown) -> Option<DirectObjUnknown> {
+        let layout = L::layout(object);
+        let offset = L::data_offset(object);
+        let target = self.alloc_layout(layout)?;
+
+        let source = object.cast::<u8>().as_ptr().sub(offset);
+        std::ptr::copy_nonoverlapping(source, target.as_ptr(), layout.size());
+
+        let copy = NonNull::new_unchecked(target.as_ptr().add(offset)).cast();
+        self.objects.push(copy);
+        Some(copy)
     }
 }
 
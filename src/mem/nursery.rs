@@ -0,0 +1,278 @@
+use crate::collect::scavenge::{AgeTable, Scavenger};
+use crate::mark::MAX_AGE;
+use crate::mem::block::OwnedMemoryBlock;
+use crate::mem::old::{references_any, OldGeneration};
+use crate::mem::HeapRegion;
+use crate::ptr::DirectObjUnknown;
+use crate::trace::AnnotatedMixedHeap;
+#[cfg(feature = "drop_heap")]
+use crate::trace::HeapObjectLayout;
+use std::alloc::Layout;
+use std::ops::Range;
+use std::ptr::NonNull;
+
+/// A thread local allocation buffer handed out by the nursery
+pub type Tlab = HeapRegion<OwnedMemoryBlock, AnnotatedMixedHeap>;
+
+/// One of the two spaces objects are copied into when they survive a minor collection
+pub type SurvivorSpace = HeapRegion<OwnedMemoryBlock, AnnotatedMixedHeap>;
+
+/// Decides how many minor collections an object must survive before it is promoted
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TenuringPolicy {
+    /// Objects are promoted once they have survived this many collections
+    Fixed(usize),
+    /// The threshold is recomputed after every collection so that the survivor space is kept at
+    /// roughly `target_survivor_ratio` percent occupancy. The threshold never exceeds
+    /// `max_threshold`.
+    Adaptive {
+        max_threshold: usize,
+        target_survivor_ratio: usize,
+    },
+}
+
+impl Default for TenuringPolicy {
+    fn default() -> Self {
+        TenuringPolicy::Adaptive {
+            max_threshold: MAX_AGE,
+            target_survivor_ratio: 50,
+        }
+    }
+}
+
+impl TenuringPolicy {
+    /// The threshold used for the first collection
+    fn initial_threshold(self) -> usize {
+        match self {
+            TenuringPolicy::Fixed(threshold) => threshold.min(MAX_AGE),
+            TenuringPolicy::Adaptive { max_threshold, .. } => max_threshold.min(MAX_AGE),
+        }
+    }
+}
+
+/// Bookkeeping for the young generation. Eden is not a single contiguous block of memory, instead
+/// the nursery acts as a budget from which TLABs are carved out as they are requested by threads.
+/// Objects which survive a minor collection are copied into one of two survivor spaces which swap
+/// roles after every collection.
+pub struct Nursery {
+    capacity: usize,
+    tlab_size: usize,
+    committed: usize,
+    retired: Vec<Tlab>,
+    from: SurvivorSpace,
+    to: SurvivorSpace,
+    tenuring: TenuringPolicy,
+    tenuring_threshold: usize,
+    ages: AgeTable,
+}
+
+impl Nursery {
+    /// Create a nursery with an eden of `capacity` bytes and two survivor spaces of
+    /// `survivor_size` bytes each.
+    pub fn new(capacity: usize, tlab_size: usize, survivor_size: usize) -> Self {
+        assert!(
+            tlab_size <= capacity,
+            "TLAB size must not exceed the size of the nursery"
+        );
+        assert!(survivor_size > 0, "Survivor spaces must be non-zero");
+
+        let layout = Layout::from_size_align(survivor_size, SurvivorSpace::heap_align())
+            .expect("Survivor size is too large");
+
+        Nursery {
+            capacity,
+            tlab_size,
+            committed: 0,
+            retired: Vec::new(),
+            from: HeapRegion::from(OwnedMemoryBlock::new(layout)),
+            to: HeapRegion::from(OwnedMemoryBlock::new(layout)),
+            tenuring: TenuringPolicy::default(),
+            tenuring_threshold: TenuringPolicy::default().initial_threshold(),
+            ages: AgeTable::default(),
+        }
+    }
+
+    /// Replace the policy used to pick the tenuring threshold
+    pub fn with_tenuring(mut self, tenuring: TenuringPolicy) -> Self {
+        self.tenuring = tenuring;
+        self.tenuring_threshold = tenuring.initial_threshold();
+        self
+    }
+
+    /// The number of collections an object must survive before it will be promoted by the next
+    /// minor collection
+    pub fn tenuring_threshold(&self) -> usize {
+        self.tenuring_threshold
+    }
+
+    /// The ages of the objects copied into the survivor space by the last minor collection
+    pub fn ages(&self) -> &AgeTable {
+        &self.ages
+    }
+
+    /// The total number of bytes eden may hand out
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of bytes which have been handed out as TLABs
+    pub fn committed(&self) -> usize {
+        self.committed
+    }
+
+    pub fn tlab_size(&self) -> usize {
+        self.tlab_size
+    }
+
+    /// Check if an object with the given layout could fit within an empty TLAB
+    pub fn fits_in_tlab(&self, layout: Layout) -> bool {
+        if layout.align() > Tlab::heap_align() {
+            return false;
+        }
+
+        match layout.align_to(Tlab::heap_align()) {
+            Ok(layout) => layout.pad_to_align().size() <= self.tlab_size,
+            Err(_) => false,
+        }
+    }
+
+    /// Hand a TLAB back to the nursery once a thread is finished allocating in it. The objects
+    /// within the TLAB remain in the nursery until they are collected.
+    pub fn retire(&mut self, tlab: Tlab) {
+        self.retired.push(tlab);
+    }
+
+    /// TLABs which have been returned to the nursery
+    pub fn retired(&self) -> &[Tlab] {
+        &self.retired
+    }
+
+    /// Attempt to carve a new TLAB out of the nursery. Returns None if the nursery does not have
+    /// enough space remaining for another TLAB.
+    pub fn take_tlab(&mut self) -> Option<Tlab> {
+        if self.committed + self.tlab_size > self.capacity {
+            return None;
+        }
+
+        let layout = Layout::from_size_align(self.tlab_size, Tlab::heap_align()).ok()?;
+        self.committed += self.tlab_size;
+
+        Some(HeapRegion::from(OwnedMemoryBlock::new(layout)))
+    }
+
+    /// The number of bytes occupied by objects in eden and the from-space
+    pub fn used(&self) -> usize {
+        let eden = self
+            .retired
+            .iter()
+            .map(HeapRegion::used_space)
+            .sum::<usize>();
+        eden + self.from.used_space()
+    }
+
+    /// The survivor space holding objects which survived the last minor collection
+    pub fn survivors(&self) -> &SurvivorSpace {
+        &self.from
+    }
+
+    /// The addresses covered by eden and the from-space
+    pub fn address_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges = vec![self.from.address_range()];
+        ranges.extend(self.retired.iter().map(HeapRegion::address_range));
+        ranges
+    }
+
+    /// Check if an object lives within the young generation
+    pub fn contains(&self, object: DirectObjUnknown) -> bool {
+        self.from.contains(object) || self.retired.iter().any(|tlab| tlab.contains(object))
+    }
+
+    /// Evacuate every live object out of eden and the from-space, then release eden and swap the
+    /// survivor spaces. When using an adaptive tenuring policy, the threshold for the next
+    /// collection is chosen from the ages of the surviving objects. Threads must retire their TLABs
+    /// before the scavenge so the objects within them can be found. With the `drop_heap` feature,
+    /// the objects left behind are dropped.
+    ///
+    /// # Safety
+    /// Every root must be a slot which is assigned to a live object. All threads must be stopped
+    /// for the duration of the scavenge.
+    ///
+    /// # Panics
+    /// Panics if the old generation runs out of room while promoting objects.
+    pub unsafe fn scavenge<I>(&mut self, old: &mut OldGeneration, roots: I)
+    where
+        I: IntoIterator<Item = NonNull<DirectObjUnknown>>,
+    {
+        let young = self.address_ranges();
+
+        // Old objects on dirty cards may be the only references to some young objects
+        let card_roots = old.preclean(|object| {
+            let addr = object.as_ptr() as usize;
+            young.iter().any(|range| range.contains(&addr))
+        });
+
+        let mut scavenger = Scavenger::new(&mut self.to, old, self.tenuring_threshold);
+        for range in young {
+            scavenger.add_young(range);
+        }
+
+        scavenger.add_old_roots(&card_roots);
+        scavenger.scavenge(roots);
+
+        let mut remembered = scavenger.promoted_objects().to_vec();
+        remembered.extend(card_roots);
+        #[cfg(feature = "drop_heap")]
+        let evacuated = scavenger.take_evacuated();
+        self.ages = scavenger.into_ages();
+
+        // Objects which were not copied out are dead, while the copies own the data of the rest
+        #[cfg(feature = "drop_heap")]
+        for region in self.retired.iter().chain(std::iter::once(&self.from)) {
+            for object in region.walk() {
+                if !evacuated.contains(&object) {
+                    AnnotatedMixedHeap::drop(object);
+                }
+            }
+        }
+
+        // Only old objects which still refer to survivors need their cards to stay dirty
+        old.clear_cards();
+        let survivors = self.to.address_range();
+        for object in remembered {
+            if references_any::<AnnotatedMixedHeap, _>(object, |target| {
+                survivors.contains(&(target.as_ptr() as usize))
+            }) {
+                old.dirty(object);
+            }
+        }
+
+        if let TenuringPolicy::Adaptive {
+            max_threshold,
+            target_survivor_ratio,
+        } = self.tenuring
+        {
+            let capacity = self.to.address_range().len();
+            let desired = capacity * target_survivor_ratio / 100;
+            self.tenuring_threshold = self.ages.compute_threshold(desired, max_threshold);
+        }
+
+        self.retired.clear();
+        self.committed = 0;
+        self.from.reset();
+        std::mem::swap(&mut self.from, &mut self.to);
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn nursery_budget_is_respected() {
+    let mut nursery = Nursery::new(4096, 1024, 512);
+
+    for _ in 0..4 {
+        let tlab = nursery.take_tlab().unwrap();
+        assert_eq!(tlab.remaining_space(), 1024);
+    }
+
+    assert_eq!(nursery.committed(), nursery.capacity());
+    assert!(nursery.take_tlab().is_none());
+}
@@ -0,0 +1,520 @@
+use crate::collect::VisitHeap;
+use crate::mark::MarkWord;
+use crate::mem::block::OwnedMemoryBlock;
+use crate::mem::card::{CardTable, CardTables};
+use crate::mem::free_list::{FragmentationStats, FreeChunk, FreeLists};
+use crate::mem::{Heap, HeapBudget, HeapRegion};
+use crate::ptr::DirectObjUnknown;
+use crate::trace::{AnnotatedMixedHeap, HeapObjectLayout, HeapObjectSetup, TraceContext};
+use std::alloc::Layout;
+use std::collections::HashMap;
+use std::iter::FlatMap;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::ptr::NonNull;
+use std::slice::Iter;
+use std::sync::Arc;
+
+/// A block of memory within the old generation
+pub type OldRegion<L = AnnotatedMixedHeap> = HeapRegion<OwnedMemoryBlock, L>;
+
+/// The old generation holds objects which have been promoted out of the nursery. It grows one
+/// region at a time for as long as its budget has room, which it shares with the large object
+/// space. Gaps left behind by the sweep are kept in
+/// size-segregated free lists and are reused before any new space is bump allocated.
+pub struct OldGeneration<L = AnnotatedMixedHeap> {
+    regions: Vec<OldRegion<L>>,
+    cards: Arc<CardTables>,
+    free: FreeLists,
+    region_size: usize,
+    budget: Arc<HeapBudget>,
+}
+
+impl<L> OldGeneration<L> {
+    /// Create an empty old generation which grows in regions of `region_size` bytes, each of which
+    /// is reserved from `budget`.
+    pub fn new(budget: Arc<HeapBudget>, region_size: usize) -> Self {
+        assert!(
+            region_size > 0,
+            "Old generation region size must be non-zero"
+        );
+
+        OldGeneration {
+            regions: Vec::new(),
+            cards: Arc::new(CardTables::new()),
+            free: FreeLists::new(),
+            region_size,
+            budget,
+        }
+    }
+
+    /// The total number of bytes this generation may grow to, given what is left of its budget
+    pub fn capacity(&self) -> usize {
+        self.committed() + self.budget.available()
+    }
+
+    /// The number of bytes reserved for regions so far
+    pub fn committed(&self) -> usize {
+        self.regions.len() * self.region_size
+    }
+
+    /// The number of bytes occupied by objects, including any padding and free chunks between them
+    pub fn used(&self) -> usize {
+        self.regions.iter().map(HeapRegion::used_space).sum()
+    }
+
+    /// The number of bytes held by free chunks which can be reused without growing
+    pub fn free_bytes(&self) -> usize {
+        self.free.free_bytes()
+    }
+
+    /// Describe how fragmented the free chunks left behind by the last sweep are
+    pub fn fragmentation(&self) -> FragmentationStats {
+        self.free.stats()
+    }
+
+    pub fn regions(&self) -> &[OldRegion<L>] {
+        &self.regions
+    }
+
+    /// Check if an object lives within this generation
+    pub fn contains(&self, object: DirectObjUnknown) -> bool {
+        self.cards.covers(object.as_ptr() as usize)
+    }
+
+    /// Add another region if the budget allows for it
+    fn grow(&mut self) -> Option<&mut OldRegion<L>> {
+        let layout =
+            Layout::from_size_align(self.region_size, OldRegion::<L>::heap_align()).ok()?;
+        if !self.budget.try_reserve(self.region_size) {
+            return None;
+        }
+
+        let region = HeapRegion::from(OwnedMemoryBlock::new(layout));
+        // Safety: The cards are only shared through `cards`, and their users never look them up
+        // while the generation is being changed
+        unsafe { self.cards.insert(CardTable::new(region.address_range())) };
+        self.regions.push(region);
+        self.regions.last_mut()
+    }
+
+    /// The card tables of this generation. A VM shares them with its threads so the write barrier
+    /// can find cards without locking the old generation. The generation only adds or removes
+    /// tables while every thread is stopped.
+    pub(crate) fn cards(&self) -> &Arc<CardTables> {
+        &self.cards
+    }
+
+    /// The write barrier. Dirties the card holding `field` when it lies within the old generation
+    /// and `value` may be young. Only references to objects outside of the old generation need to
+    /// be remembered.
+    pub fn write_barrier(&self, field: NonNull<()>, value: DirectObjUnknown) {
+        self.cards
+            .write_barrier(field.as_ptr() as usize, value.as_ptr() as usize);
+    }
+
+    /// Dirty the card holding an address if it lies within the old generation
+    pub fn dirty(&self, addr: NonNull<()>) {
+        self.cards.dirty(addr.as_ptr() as usize);
+    }
+
+    pub fn is_dirty(&self, addr: NonNull<()>) -> bool {
+        self.cards.is_dirty(addr.as_ptr() as usize)
+    }
+
+    /// Clean every card in the old generation
+    pub fn clear_cards(&self) {
+        self.cards.clear();
+    }
+}
+
+impl<L: HeapObjectLayout> OldGeneration<L> {
+    /// Pre-clean the card table ahead of a minor collection. Dirty cards where none of the objects
+    /// refer to a young object any more are cleaned. Returns the objects on the remaining dirty
+    /// cards, which must be treated as roots by the collection.
+    ///
+    /// # Safety
+    /// All threads must be stopped and every object in the old generation must be live.
+    pub unsafe fn preclean<F>(&self, is_young: F) -> Vec<DirectObjUnknown>
+    where
+        F: Fn(DirectObjUnknown) -> bool,
+    {
+        let mut roots = Vec::new();
+
+        for region in &self.regions {
+            let cards = match self.cards.find(region.address_range().start) {
+                Some(cards) => cards,
+                None => continue,
+            };
+
+            for card in cards.dirty_cards() {
+                let objects = region.objects_overlapping(card.clone());
+                let young = objects
+                    .iter()
+                    .filter(|object| references_any::<L, _>(**object, &is_young))
+                    .copied()
+                    .collect::<Vec<_>>();
+
+                if young.is_empty() {
+                    cards.clean(card.start);
+                }
+                roots.extend(young);
+            }
+        }
+
+        roots.sort_unstable();
+        roots.dedup();
+        roots
+    }
+
+    /// Free every object which has not been marked and remove the mark from all remaining objects.
+    /// Each region is walked object by object, and runs of dead objects and fillers are coalesced
+    /// into a single filler which is added to the free lists. A run reaching the end of the last
+    /// region is handed back to the bump allocator instead. With the `drop_heap` feature, dead objects
+    /// are dropped before their memory is freed. Returns the number of bytes freed.
+    ///
+    /// # Safety
+    /// The mark phase must have completed so that all reachable objects in this generation are
+    /// marked.
+    pub unsafe fn sweep(&mut self) -> usize {
+        let mut freed = 0;
+        let last = self.regions.len().saturating_sub(1);
+        self.free.clear();
+
+        for (idx, region) in self.regions.iter_mut().enumerate() {
+            let allocations = region.walk_allocations().collect::<Vec<_>>();
+            region.objects.clear();
+
+            let mut gap: Option<FreeChunk> = None;
+            for object in allocations {
+                if !L::is_filler(object) {
+                    let mark = L::mark(object);
+                    if mark.is_marked() {
+                        mark.unmark();
+                        region.objects.push(object);
+
+                        if let Some(chunk) = gap.take() {
+                            L::write_filler(chunk.start, chunk.size);
+                            self.free.insert(chunk);
+                        }
+                        continue;
+                    }
+
+                    freed += L::layout(object).size();
+                    #[cfg(feature = "drop_heap")]
+                    L::drop(object);
+                }
+
+                let size = OldRegion::<L>::allocation_size(L::layout(object));
+                match &mut gap {
+                    Some(chunk) => chunk.size += size,
+                    None => {
+                        gap = Some(FreeChunk {
+                            region: idx,
+                            start: allocation_start::<L>(object),
+                            size,
+                        })
+                    }
+                }
+            }
+
+            // Only the last region is bump allocated in, so the tail of any other is a free chunk
+            match gap {
+                Some(chunk) if idx == last => region.remaining = chunk.start,
+                Some(chunk) => {
+                    L::write_filler(chunk.start, chunk.size);
+                    self.free.insert(chunk);
+                }
+                None => {}
+            }
+        }
+
+        freed
+    }
+
+    /// Slide every live object towards the start of the old generation to remove the gaps left
+    /// behind by the sweep. Since every object is referenced through a single slot in the
+    /// reference table, moving an object only requires rewriting its slot. Regions left empty by
+    /// compaction are released. Returns the number of bytes which were released.
+    ///
+    /// Regions holding objects without a slot are pinned and left untouched. Raw edges may not
+    /// refer to objects in the old generation, so every object with a slot can be moved.
+    ///
+    /// # Safety
+    /// The heap must have been swept, and `slots` must map every object referenced by the
+    /// reference table to its slot. All threads must be stopped.
+    pub unsafe fn compact(
+        &mut self,
+        slots: &HashMap<DirectObjUnknown, NonNull<DirectObjUnknown>>,
+    ) -> usize {
+        // Every gap is about to be squeezed out
+        self.free.clear();
+
+        let live = self
+            .regions
+            .iter_mut()
+            .map(|region| std::mem::take(&mut region.objects))
+            .collect::<Vec<_>>();
+
+        let mut target = 0;
+        let mut cursor = self.regions.first().map(OldRegion::<L>::start);
+
+        for (idx, objects) in live.into_iter().enumerate() {
+            if objects.iter().any(|object| !slots.contains_key(object)) {
+                // Regions between the target and the pinned region have already been emptied
+                if let Some(cursor) = cursor.filter(|_| target < idx) {
+                    self.regions[target].remaining = cursor;
+                    for region in &mut self.regions[target + 1..idx] {
+                        region.remaining = region.start();
+                    }
+                }
+
+                self.regions[idx].objects = objects;
+                target = idx + 1;
+                cursor = self.regions.get(target).map(OldRegion::<L>::start);
+                continue;
+            }
+
+            for object in objects {
+                let offset = L::data_offset(object);
+                let size = L::layout(object).size();
+                let source = allocation_start::<L>(object);
+
+                // Objects only ever slide down, so the target never passes the current region
+                let mut dest = cursor.unwrap();
+                if self.regions[target].end() - (dest.as_ptr() as usize) < size {
+                    self.regions[target].remaining = dest;
+                    target += 1;
+                    dest = self.regions[target].start();
+                }
+
+                std::ptr::copy(source.as_ptr(), dest.as_ptr(), size);
+
+                let moved = NonNull::new_unchecked(dest.as_ptr().add(offset)).cast();
+                slots[&object].as_ptr().write(moved);
+                self.regions[target].objects.push(moved);
+
+                let next = dest
+                    .as_ptr()
+                    .add(OldRegion::<L>::allocation_size(L::layout(moved)));
+                cursor = Some(NonNull::new_unchecked(next));
+            }
+        }
+
+        if let Some(cursor) = cursor {
+            self.regions[target].remaining = cursor;
+            for region in &mut self.regions[target + 1..] {
+                region.remaining = region.start();
+            }
+        }
+
+        // Release every region after the last one which still holds objects
+        let keep = self
+            .regions
+            .iter()
+            .rposition(|region| region.used_space() > 0)
+            .map_or(0, |idx| idx + 1);
+        let released = (self.regions.len() - keep) * self.region_size;
+        for region in &self.regions[keep..] {
+            // As in `grow`, nothing else looks up cards while the generation is being changed
+            self.cards.remove(region.address_range().start);
+        }
+        self.regions.truncate(keep);
+        self.budget.release(released);
+
+        // Objects have moved, so the cards no longer describe them
+        self.clear_cards();
+        released
+    }
+
+    /// Dirty the card of every object which refers to an object within one of `ranges`. Used to
+    /// rebuild the card table once objects have been moved.
+    ///
+    /// # Safety
+    /// All threads must be stopped and every object in the old generation must be live.
+    pub unsafe fn remember_references_into(&self, ranges: &[Range<usize>]) {
+        for object in self.iter_entries() {
+            if references_any::<L, _>(object, |target| {
+                let addr = target.as_ptr() as usize;
+                ranges.iter().any(|range| range.contains(&addr))
+            }) {
+                self.dirty(object);
+            }
+        }
+    }
+
+    /// A lower bound on the number of bytes which can be promoted into this generation before its
+    /// budget runs out, given that every object is smaller than `threshold` bytes. The tail of
+    /// each region and free chunk may be too small to hold the next object, and a free chunk must
+    /// also leave room for a filler behind it.
+    pub fn promotion_headroom(&self, threshold: usize) -> usize {
+        let largest = match Layout::from_size_align(threshold, 1) {
+            Ok(layout) => OldRegion::<L>::allocation_size(layout),
+            Err(_) => return 0,
+        };
+        let usable = |free: usize| free.saturating_sub(largest);
+
+        let free = self.free.usable_bytes(largest + L::min_filler_size());
+        let last = self
+            .regions
+            .last()
+            .map_or(0, |region| usable(region.remaining_space()));
+        free + last + self.budget.available() / self.region_size * usable(self.region_size)
+    }
+
+    /// Find space for an allocation, preferring a free chunk over bump allocating in the last
+    /// region. Returns the index of the region holding the allocation. The caller is responsible
+    /// for writing an object to the returned memory and passing it to `insert_object`.
+    fn alloc_layout(&mut self, layout: Layout) -> Option<(usize, NonNull<u8>)> {
+        let size = OldRegion::<L>::allocation_size(layout);
+
+        if let Some(chunk) = self.free.take(size, L::min_filler_size()) {
+            if chunk.size > size {
+                let rest = FreeChunk {
+                    region: chunk.region,
+                    start: unsafe { NonNull::new_unchecked(chunk.start.as_ptr().add(size)) },
+                    size: chunk.size - size,
+                };
+
+                // Free chunks are only ever taken from memory owned by this generation, and the
+                // remainder is large enough to hold a filler.
+                unsafe { L::write_filler(rest.start, rest.size) };
+                self.free.insert(rest);
+            }
+
+            return Some((chunk.region, chunk.start));
+        }
+
+        if let Some(ptr) = self
+            .regions
+            .last_mut()
+            .and_then(|region| region.alloc_layout(layout))
+        {
+            return Some((self.regions.len() - 1, ptr));
+        }
+
+        let ptr = self.grow()?.alloc_layout(layout)?;
+        Some((self.regions.len() - 1, ptr))
+    }
+
+    /// Record a newly allocated object, keeping the objects of its region sorted by address
+    fn insert_object(&mut self, region: usize, object: DirectObjUnknown) {
+        let objects = &mut self.regions[region].objects;
+        let idx = objects.partition_point(|existing| *existing < object);
+        objects.insert(idx, object);
+    }
+
+    /// Promote an object into the old generation by copying it. Returns None if the old generation
+    /// is full.
+    ///
+    /// # Safety
+    /// `object` must point to a live object which was allocated using the same layout.
+    pub unsafe fn copy_object(&mut self, object: DirectObjUnknown) -> Option<DirectObjUnknown> {
+        let layout = L::layout(object);
+        let offset = L::data_offset(object);
+        let (region, target) = self.alloc_layout(layout)?;
+
+        let source = allocation_start::<L>(object);
+        std::ptr::copy_nonoverlapping(source.as_ptr(), target.as_ptr(), layout.size());
+
+        let copy = NonNull::new_unchecked(target.as_ptr().add(offset)).cast();
+        self.insert_object(region, copy);
+        Some(copy)
+    }
+}
+
+impl<T, L> Heap<T> for OldGeneration<L>
+where
+    L: HeapObjectLayout + HeapObjectSetup<T>,
+{
+    fn try_alloc_uninit(&mut self) -> Option<NonNull<MaybeUninit<T>>> {
+        let layout = L::wrap_layout(Layout::new::<T>());
+        if layout.align() > OldRegion::<L>::heap_align() {
+            return None;
+        }
+
+        let (region, allocated) = self.alloc_layout(layout)?;
+        let object = unsafe { L::init_object(allocated, layout) };
+        self.insert_object(region, object.cast());
+        Some(object.cast())
+    }
+}
+
+/// The start of the allocation holding an object
+///
+/// # Safety
+/// `object` must point to a live object which was allocated using this layout.
+unsafe fn allocation_start<L: HeapObjectLayout>(object: DirectObjUnknown) -> NonNull<u8> {
+    NonNull::new_unchecked(object.cast::<u8>().as_ptr().sub(L::data_offset(object)))
+}
+
+/// Check if any of the strong references held by an object match a predicate
+///
+/// # Safety
+/// `object` must point to a live object which was allocated using this layout.
+pub(crate) unsafe fn references_any<L, F>(object: DirectObjUnknown, predicate: F) -> bool
+where
+    L: HeapObjectLayout,
+    F: Fn(DirectObjUnknown) -> bool,
+{
+    let mut cxt = TraceContext::default();
+    L::trace(object, &mut cxt);
+
+    while let Some(edge) = cxt.pop_mark() {
+        if predicate(edge) {
+            return true;
+        }
+    }
+
+    false
+}
+
+unsafe impl<'a, L: HeapObjectLayout> VisitHeap for &'a OldGeneration<L> {
+    type Layout = L;
+    #[allow(clippy::type_complexity)]
+    type EntryIter = FlatMap<
+        Iter<'a, OldRegion<L>>,
+        <&'a OldRegion<L> as VisitHeap>::EntryIter,
+        fn(&'a OldRegion<L>) -> <&'a OldRegion<L> as VisitHeap>::EntryIter,
+    >;
+
+    fn iter_entries(self) -> Self::EntryIter {
+        self.regions.iter().flat_map(VisitHeap::iter_entries)
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn sweep_reuses_gaps_through_free_lists() {
+    let mut old = OldGeneration::<AnnotatedMixedHeap>::new(Arc::new(HeapBudget::new(4096)), 4096);
+    let objects = (0..8u64)
+        .map(|idx| old.try_push_to_heap([idx; 3]).ok().unwrap().cast::<()>())
+        .collect::<Vec<_>>();
+    let size = old.used() / objects.len();
+
+    for idx in [0, 3, 4] {
+        unsafe { AnnotatedMixedHeap::mark(objects[idx]).set_mark() };
+    }
+
+    // The trailing run of dead objects is returned to the bump allocator
+    assert_eq!(unsafe { old.sweep() }, 5 * size);
+    assert_eq!(old.used(), 5 * size);
+    assert_eq!(
+        old.fragmentation(),
+        FragmentationStats {
+            free_bytes: 2 * size,
+            free_chunks: 1,
+            largest_chunk: 2 * size,
+            fragmentation: 0.0,
+        }
+    );
+
+    let reused = old.try_push_to_heap([8u64; 3]).ok().unwrap().cast::<()>();
+    assert_eq!(reused, objects[1]);
+    assert_eq!(old.free_bytes(), size);
+    assert_eq!(old.used(), 5 * size);
+    assert_eq!(
+        unsafe { old.regions()[0].walk().collect::<Vec<_>>() },
+        [objects[0], objects[1], objects[3], objects[4]]
+    );
+}
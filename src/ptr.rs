@@ -1,5 +1,8 @@
-use std::fmt::{Formatter, Pointer};
-use std::ops::Deref;
+use crate::alloc::ThreadAllocator;
+use crate::trace::{Trace, TraceContext};
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Pointer};
 use std::ptr::NonNull;
 
 /// Placeholder so it can be swapped out later with a struct if needed
@@ -8,17 +11,44 @@ pub type DirectObjPtr<T> = NonNull<T>;
 /// A direct pointer to an object of unknown type
 pub type DirectObjUnknown = DirectObjPtr<()>;
 
-#[derive(Copy, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Hash, Ord, PartialOrd, Eq, PartialEq)]
 #[repr(transparent)]
 pub struct GcPtr<T: ?Sized> {
     ptr: NonNull<DirectObjPtr<T>>,
 }
 
+// Implemented by hand since deriving would require T: Copy
+impl<T: ?Sized> Clone for GcPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for GcPtr<T> {}
+
 impl<T: ?Sized> GcPtr<T> {
+    /// Create a pointer from a slot in the reference table.
+    ///
+    /// # Safety
+    /// `slot` must be a slot within a `RefTable` which has been assigned a direct pointer.
+    pub(crate) unsafe fn from_slot(slot: NonNull<DirectObjPtr<T>>) -> Self {
+        GcPtr { ptr: slot }
+    }
+
+    /// The slot in the reference table this pointer refers to
+    pub(crate) fn slot(&self) -> NonNull<DirectObjUnknown> {
+        self.ptr.cast()
+    }
+
     /// Get the direct pointer to this object in memory. This pointer may shift during garbage
     /// collection.
-    pub fn direct_ptr(&self) -> *mut T {
-        unsafe { (&*self.ptr.as_ptr()).as_ptr() }
+    ///
+    /// # Safety
+    /// The pointer's slot must not have been freed. A slot is only guaranteed to stay allocated
+    /// while it is a root or while its object is reachable from a root, since the slots of dead
+    /// objects are freed and their blocks may be released.
+    pub unsafe fn direct_ptr(&self) -> *mut T {
+        (*self.ptr.as_ptr()).as_ptr()
     }
 
     // pub unsafe fn as_ref_unchecked(&self) -> &T {
@@ -30,12 +60,71 @@ impl<T: ?Sized> GcPtr<T> {
     // }
 }
 
+/// Anything which identifies an object to the VM, which is either a `GcPtr` or a `Root`. The trait
+/// is sealed, so only pointers handed out by the VM can be used.
+pub trait Handle<T: ?Sized>: Copy {
+    #[doc(hidden)]
+    fn gc_ptr(&self, _: Sealed) -> GcPtr<T>;
+}
+
+/// Keeps `Handle::gc_ptr` from being implemented or called outside of this crate
+#[doc(hidden)]
+pub struct Sealed(());
+
+impl Sealed {
+    pub(crate) fn new() -> Self {
+        Sealed(())
+    }
+}
+
+impl<T: ?Sized> Handle<T> for GcPtr<T> {
+    fn gc_ptr(&self, _: Sealed) -> GcPtr<T> {
+        *self
+    }
+}
+
+/// The pointer behind a handle
+pub(crate) fn gc_ptr<T: ?Sized, H: Handle<T>>(handle: &H) -> GcPtr<T> {
+    handle.gc_ptr(Sealed::new())
+}
+
 impl<T: ?Sized> Pointer for GcPtr<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         std::fmt::Pointer::fmt(&self.ptr, f)
     }
 }
 
+/// A mutable field holding a `GcPtr`. Stores go through the write barrier so the collector can
+/// find old objects which refer to young objects.
+#[repr(transparent)]
+pub struct GcCell<T: ?Sized> {
+    ptr: Cell<GcPtr<T>>,
+}
+
+impl<T: ?Sized> GcCell<T> {
+    pub fn new<H: Handle<T>>(ptr: H) -> Self {
+        GcCell {
+            ptr: Cell::new(gc_ptr(&ptr)),
+        }
+    }
+
+    pub fn get(&self) -> GcPtr<T> {
+        self.ptr.get()
+    }
+
+    /// Store a new pointer in this cell and record the store with the write barrier of the
+    /// allocator's virtual machine.
+    pub fn set<U: Trace, H: Handle<T>>(&self, allocator: &ThreadAllocator<'_, U>, value: H) {
+        let value = gc_ptr(&value);
+        allocator.write_barrier(NonNull::from(self).cast(), value, || self.ptr.set(value));
+    }
+}
+
+impl<T: ?Sized> Trace for GcCell<T> {
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        cxt.visit(&self.ptr.get());
+    }
+}
 
 /// I'm not happy with how this looks, but it should be completely safe. To access data it will need
 /// to be used with a `ThreadAllocator` to ensure that it meets the lifetime requirements and to
@@ -43,16 +132,120 @@ impl<T: ?Sized> Pointer for GcPtr<T> {
 /// an error when used against a different vm, but will continue to work for any `ThreadAllocator`
 /// on the vm it was allocated for. It will also be able to deny objects that have since been
 /// deleted.
+///
+/// Objects are detected as deleted by comparing the generation of the slot in the reference table
+/// against the generation it had when this pointer was created. Slots change generation every time
+/// they are freed, so a stale pointer is caught even after its slot is reused.
+#[cfg(feature = "slot_generations")]
 pub struct SafeGcPtr<T: ?Sized> {
     vm: NonNull<()>,
-    ptr: NonNull<T>,
+    ptr: GcPtr<T>,
     generation: u64,
 }
 
+#[cfg(feature = "slot_generations")]
+impl<T: ?Sized> Clone for SafeGcPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+#[cfg(feature = "slot_generations")]
+impl<T: ?Sized> Copy for SafeGcPtr<T> {}
+
+#[cfg(feature = "slot_generations")]
+impl<T: ?Sized> SafeGcPtr<T> {
+    /// Create a checked pointer to an object. Returns None if the object was not allocated by the
+    /// allocator's vm.
+    pub fn new<U: Trace, H: Handle<T>>(allocator: &ThreadAllocator<'_, U>, ptr: H) -> Option<Self> {
+        let ptr = gc_ptr(&ptr);
+        let generation = allocator.vm().ref_table().generation(ptr.slot().cast())?;
 
+        Some(SafeGcPtr {
+            vm: NonNull::from(allocator.vm()).cast(),
+            ptr,
+            generation,
+        })
+    }
+
+    /// Check that the object is still alive and belongs to the allocator's vm before handing out
+    /// the underlying pointer.
+    pub fn get<U: Trace>(
+        &self,
+        allocator: &ThreadAllocator<'_, U>,
+    ) -> Result<GcPtr<T>, StalePtrError> {
+        if NonNull::from(allocator.vm()).cast() != self.vm {
+            return Err(StalePtrError::ForeignVm);
+        }
+
+        match allocator
+            .vm()
+            .ref_table()
+            .generation(self.ptr.slot().cast())
+        {
+            Some(generation) if generation == self.generation => Ok(self.ptr),
+            _ => Err(StalePtrError::Dead),
+        }
+    }
+}
+
+/// The reason a `SafeGcPtr` could not be used
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StalePtrError {
+    /// The pointer was created for an object in a different vm
+    ForeignVm,
+    /// The object has been collected since the pointer was created
+    Dead,
+}
 
-// TODO: Implement generational indices for weak GC pointers
-// pub struct WeakGcPtr<T> {
-//     ptr: GcPtr<T>,
-//     generation: u64,
-// }
+impl Display for StalePtrError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StalePtrError::ForeignVm => write!(f, "pointer belongs to a different vm"),
+            StalePtrError::Dead => {
+                write!(f, "pointer refers to an object which has been collected")
+            }
+        }
+    }
+}
+
+impl Error for StalePtrError {}
+
+#[test]
+#[cfg(all(test, feature = "slot_generations"))]
+fn safe_pointers_detect_stale_slots() {
+    use crate::alloc::VirtualMachine;
+
+    let vm = VirtualMachine::<u64>::test_builder().build();
+    let allocator = vm.attach_thread();
+
+    let (live, dead) = {
+        let scope = allocator.handle_scope();
+        let live = allocator.allocate(1).unwrap();
+        let dead = scope.allocate(2).unwrap();
+
+        let live = SafeGcPtr::new(&allocator, live).unwrap();
+        let dead = SafeGcPtr::new(&allocator, dead).unwrap();
+        assert!(dead.get(&allocator).is_ok());
+        (live, dead)
+    };
+
+    vm.collect_minor();
+    assert_eq!(dead.get(&allocator).err(), Some(StalePtrError::Dead));
+
+    // Reusing the slot must not revive the stale pointer
+    let reused = (0..4)
+        .map(|x| allocator.allocate(x).unwrap())
+        .collect::<Vec<_>>();
+    assert!(reused.iter().any(|ptr| ptr.slot() == dead.ptr.slot()));
+    assert_eq!(dead.get(&allocator).err(), Some(StalePtrError::Dead));
+
+    let ptr = live.get(&allocator).unwrap();
+    assert_eq!(unsafe { *ptr.direct_ptr() }, 1);
+
+    let other = VirtualMachine::<u64>::test_builder().build::<u64>();
+    assert_eq!(
+        live.get(&other.attach_thread()).err(),
+        Some(StalePtrError::ForeignVm)
+    );
+}
@@ -1,8 +1,12 @@
 use crate::ptr::DirectObjPtr;
-use parking_lot::Mutex;
-use std::convert::TryInto;
+#[cfg(feature = "slot_generations")]
+use crate::sync::AtomicU64;
+use crate::sync::{AtomicBool, AtomicPtr, AtomicUsize, Mutex, MutexGuard};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
 use std::ptr::NonNull;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::Ordering;
 
 #[derive(Copy, Clone)]
 union ObjectOrNextEmpty<T: ?Sized> {
@@ -39,28 +43,57 @@ impl<T: ?Sized> Default for ObjectOrNextEmpty<T> {
     }
 }
 
-/// Size of RefTableBlock. Highly arbitrary, but attempts to be a multiple/factor of the page size.
-const BLOCK_SIZE: usize = 4096;
+/// Empty slots held by a single thread, so slots can be claimed without touching the shared chain
+/// of empty slots. Refilled from the table a block at a time.
+#[derive(Debug, Default)]
+pub struct SlotCache {
+    slots: Vec<NonNull<()>>,
+}
+
+/// The cached slots are only reachable through the cache
+unsafe impl Send for SlotCache {}
+
+impl SlotCache {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+/// Default number of slots in a RefTableBlock. Highly arbitrary, but attempts to be a
+/// multiple/factor of the page size.
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
 
-#[repr(transparent)]
 struct RefTableBlock<T: ?Sized> {
-    ptr: Box<[ObjectOrNextEmpty<T>; BLOCK_SIZE]>,
+    ptr: Box<[ObjectOrNextEmpty<T>]>,
+    /// Whether each slot is on the chain of empty slots, so live slots can be found without
+    /// walking the chain
+    free: Box<[AtomicBool]>,
+    /// The generation of each slot, which changes every time the slot is freed
+    #[cfg(feature = "slot_generations")]
+    generations: Box<[AtomicU64]>,
 }
 
 impl<T: ?Sized> RefTableBlock<T> {
-    pub fn new() -> Self {
-        let mut vec = Vec::with_capacity(BLOCK_SIZE);
-        vec.resize_with(BLOCK_SIZE, || ObjectOrNextEmpty { next_empty: None });
+    pub fn new(block_size: usize) -> Self {
+        assert!(block_size >= 2, "RefTableBlock must hold at least 2 slots");
+        let mut vec = Vec::with_capacity(block_size);
+        vec.resize_with(block_size, || ObjectOrNextEmpty { next_empty: None });
 
-        for idx in 0..BLOCK_SIZE - 1 {
+        for idx in 0..block_size - 1 {
             vec[idx] = ObjectOrNextEmpty {
                 next_empty: Some(NonNull::new(&vec[idx + 1] as *const _ as *mut _).unwrap()),
             }
         }
 
-        match vec.into_boxed_slice().try_into() {
-            Ok(ptr) => RefTableBlock { ptr },
-            Err(_) => unreachable!(),
+        RefTableBlock {
+            ptr: vec.into_boxed_slice(),
+            free: (0..block_size).map(|_| AtomicBool::new(true)).collect(),
+            #[cfg(feature = "slot_generations")]
+            generations: (0..block_size).map(|_| AtomicU64::new(0)).collect(),
         }
     }
 
@@ -68,51 +101,182 @@ impl<T: ?Sized> RefTableBlock<T> {
         &mut self,
         new_end: NonNull<ObjectOrNextEmpty<T>>,
     ) -> *mut ObjectOrNextEmpty<T> {
-        self.ptr[BLOCK_SIZE - 1] = ObjectOrNextEmpty {
+        let last = self.ptr.len() - 1;
+        self.ptr[last] = ObjectOrNextEmpty {
             next_empty: Some(new_end),
         };
 
         &mut self.ptr[0] as *mut _
     }
+
+    /// The address of the first slot in this block
+    fn start(&self) -> usize {
+        self.ptr.as_ptr() as usize
+    }
+
+    /// The position of a slot within this block
+    fn index_of(&self, slot: NonNull<ObjectOrNextEmpty<T>>) -> Option<usize> {
+        if !self
+            .ptr
+            .as_ptr_range()
+            .contains(&(slot.as_ptr() as *const _))
+        {
+            return None;
+        }
+
+        let offset = slot.as_ptr() as usize - self.start();
+        Some(offset / std::mem::size_of::<ObjectOrNextEmpty<T>>())
+    }
+
+    /// Record whether a slot is on the chain of empty slots
+    fn set_free(&self, slot: NonNull<ObjectOrNextEmpty<T>>, free: bool) {
+        if let Some(idx) = self.index_of(slot) {
+            self.free[idx].store(free, Ordering::SeqCst);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.free.iter().all(|free| free.load(Ordering::SeqCst))
+    }
+
+    /// Every slot along with whether it is on the chain of empty slots
+    fn slots(&self) -> impl Iterator<Item = (NonNull<ObjectOrNextEmpty<T>>, bool)> + '_ {
+        let free = self.free.iter().map(|free| free.load(Ordering::SeqCst));
+        self.ptr.iter().map(NonNull::from).zip(free)
+    }
+
+    /// Start every slot in this block at the given generation
+    #[cfg(feature = "slot_generations")]
+    fn set_generation(&self, generation: u64) {
+        for slot in self.generations.iter() {
+            slot.store(generation, Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(feature = "slot_generations")]
+    fn generation_of(&self, slot: NonNull<ObjectOrNextEmpty<T>>) -> Option<&AtomicU64> {
+        Some(&self.generations[self.index_of(slot)?])
+    }
+}
+
+/// Every block of a table, sorted by address
+type BlockIndex<T> = Vec<NonNull<RefTableBlock<T>>>;
+
+/// Indexes and blocks which have been replaced or released, but may still be in use by a thread
+/// searching an older index
+struct Retired<T: ?Sized> {
+    indexes: Vec<Pin<Box<BlockIndex<T>>>>,
+    blocks: Vec<Pin<Box<RefTableBlock<T>>>>,
 }
 
 pub struct RefTable<T: ?Sized> {
-    blocks: Mutex<Vec<RefTableBlock<T>>>,
+    /// Every block of slots, sorted by address. Blocks are pinned since the index refers to them.
+    blocks: Mutex<Vec<Pin<Box<RefTableBlock<T>>>>>,
+    /// A copy of `blocks` which is replaced whenever blocks are added or released, so slots can be
+    /// looked up without taking the lock
+    index: AtomicPtr<BlockIndex<T>>,
+    /// The number of threads currently searching the index
+    readers: AtomicUsize,
+    retired: Mutex<Retired<T>>,
     empty: AtomicPtr<ObjectOrNextEmpty<T>>,
+    block_size: usize,
+    /// Live slots which are no longer roots. Their objects are only kept alive by references from
+    /// within the heap.
+    unrooted: Mutex<HashSet<NonNull<ObjectOrNextEmpty<T>>>>,
+    /// The number of roots held on slots beyond the first, such as when a weak pointer is upgraded
+    /// while its object is still rooted elsewhere. Always locked after `unrooted`.
+    extra_roots: Mutex<HashMap<NonNull<ObjectOrNextEmpty<T>>, usize>>,
+    /// The number of slots currently assigned to objects
+    live: AtomicUsize,
+    /// The largest number of live slots since blocks were last released
+    peak: AtomicUsize,
+    /// The next generation handed out to freed slots. Every generation is only used once, so a
+    /// stale generation never matches a slot which has since been reused.
+    #[cfg(feature = "slot_generations")]
+    epoch: AtomicU64,
 }
 
+/// Slots are claimed through atomic operations and blocks are only added while holding the lock.
+/// Lookups which skip the lock go through the index, which is never freed while being searched.
+unsafe impl<T: ?Sized + Send> Send for RefTable<T> {}
+unsafe impl<T: ?Sized + Send> Sync for RefTable<T> {}
+
 impl<T> Default for RefTable<T> {
     fn default() -> Self {
-        let first_block = RefTableBlock::new();
+        Self::with_block_size(DEFAULT_BLOCK_SIZE)
+    }
+}
+
+impl<T> RefTable<T> {
+    /// Create a new reference table which grows `block_size` slots at a time.
+    pub fn with_block_size(block_size: usize) -> Self {
+        let first_block = Box::pin(RefTableBlock::new(block_size));
         let empty_ptr = AtomicPtr::new(&first_block.ptr[0] as *const _ as *mut _);
+        let index = Box::new(vec![NonNull::from(&*first_block)]);
 
         RefTable {
             blocks: Mutex::new(vec![first_block]),
+            index: AtomicPtr::new(Box::into_raw(index)),
+            readers: AtomicUsize::new(0),
+            retired: Mutex::new(Retired {
+                indexes: Vec::new(),
+                blocks: Vec::new(),
+            }),
             empty: empty_ptr,
+            block_size,
+            unrooted: Mutex::new(HashSet::new()),
+            extra_roots: Mutex::new(HashMap::new()),
+            live: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            #[cfg(feature = "slot_generations")]
+            epoch: AtomicU64::new(1),
         }
     }
-}
 
-impl<T> RefTable<T> {
+    /// The number of slots added to the table each time it grows
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
     /// Frees positions in the reference table for reuse.
     ///
     /// # Safety
     /// Items in the iterator must have been provided by this RefTable. Items also must not be in
     /// use. Using any of the pointers provided after calling this method is undefined behavior.
     pub unsafe fn free_slots<I: Iterator<Item = NonNull<DirectObjPtr<T>>>>(&self, slots: I) {
-        let mut slots = slots.map(|x| x.cast::<ObjectOrNextEmpty<T>>());
+        let slots = slots
+            .map(|x| x.cast::<ObjectOrNextEmpty<T>>())
+            .collect::<Vec<_>>();
+
+        self.read_index(|| {
+            for slot in &slots {
+                if let Some((_, block)) = self.find_block(*slot) {
+                    block.set_free(*slot, true);
 
+                    // Pointers to the objects which held the slot become stale
+                    #[cfg(feature = "slot_generations")]
+                    if let Some(generation) = block.generation_of(*slot) {
+                        generation.store(self.next_generation(), Ordering::SeqCst);
+                    }
+                }
+            }
+        });
+
+        let mut slots = slots.into_iter();
         let first_slot = match slots.next() {
             Some(v) => v,
             None => return,
         };
 
         let mut last_slot = first_slot;
+        let mut count = 1;
 
         for slot in slots {
             last_slot.as_mut().next_empty = Some(slot);
             last_slot = slot;
+            count += 1;
         }
+        self.live.fetch_sub(count, Ordering::SeqCst);
 
         // Fit the new chain into the empty items list
         loop {
@@ -135,13 +299,348 @@ impl<T> RefTable<T> {
         }
     }
 
+    /// Run `f` while counted as a reader of the index, which keeps the index and the blocks it
+    /// refers to from being freed
+    fn read_index<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let result = f();
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Find the block holding a slot with a binary search of the index, along with its position
+    ///
+    /// # Safety
+    /// The caller must either hold the blocks lock or be within `read_index`.
+    unsafe fn find_block(
+        &self,
+        slot: NonNull<ObjectOrNextEmpty<T>>,
+    ) -> Option<(usize, &RefTableBlock<T>)> {
+        let index = &*self.index.load(Ordering::SeqCst);
+        let addr = slot.as_ptr() as usize;
+        let idx = index.partition_point(|block| block.as_ref().start() <= addr);
+
+        let idx = idx.checked_sub(1)?;
+        let block = index[idx].as_ref();
+        block.index_of(slot).map(|_| (idx, block))
+    }
+
+    /// Replace the index after the blocks have changed. The previous index and any `released`
+    /// blocks are freed once no thread is searching the index. Must be called while holding the
+    /// blocks lock.
+    fn publish(
+        &self,
+        blocks: &[Pin<Box<RefTableBlock<T>>>],
+        released: Vec<Pin<Box<RefTableBlock<T>>>>,
+    ) {
+        let index = blocks
+            .iter()
+            .map(|block| NonNull::from(&**block))
+            .collect::<BlockIndex<T>>();
+        let previous = self
+            .index
+            .swap(Box::into_raw(Box::new(index)), Ordering::SeqCst);
+
+        let mut retired = self.retired.lock();
+        // Safety: Every index is created by a Box and is only replaced once
+        retired
+            .indexes
+            .push(unsafe { Box::into_pin(Box::from_raw(previous)) });
+        retired.blocks.extend(released);
+
+        // Threads which start searching after the swap can only find the new index. Reading the
+        // count through a read-modify-write orders it with the increments made by readers.
+        if self.readers.fetch_add(0, Ordering::SeqCst) == 0 {
+            retired.indexes.clear();
+            retired.blocks.clear();
+        }
+    }
+
+    #[cfg(feature = "slot_generations")]
+    fn next_generation(&self) -> u64 {
+        self.epoch.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// The current generation of a slot, or None if the slot does not belong to this table. The
+    /// generation changes every time the slot is freed.
+    #[cfg(feature = "slot_generations")]
+    pub fn generation(&self, slot: NonNull<DirectObjPtr<T>>) -> Option<u64> {
+        self.read_index(|| unsafe {
+            let (_, block) = self.find_block(slot.cast())?;
+            block
+                .generation_of(slot.cast())
+                .map(|generation| generation.load(Ordering::SeqCst))
+        })
+    }
+
+    /// Check if a slot belongs to this table
+    pub fn contains(&self, slot: NonNull<DirectObjPtr<T>>) -> bool {
+        self.read_index(|| unsafe { self.find_block(slot.cast()).is_some() })
+    }
+
+    /// Collect every slot which is currently assigned to an object.
+    ///
+    /// # Safety
+    /// No other thread may claim or free slots while the table is being scanned.
+    pub unsafe fn live_slots(&self) -> Vec<NonNull<DirectObjPtr<T>>> {
+        let blocks = self.blocks.lock();
+
+        blocks
+            .iter()
+            .flat_map(|block| block.slots())
+            .filter(|(_, free)| !free)
+            .map(|(slot, _)| slot.cast())
+            .collect()
+    }
+
+    /// Every live slot which is still a root
+    ///
+    /// # Safety
+    /// No other thread may claim or free slots while the table is being scanned.
+    pub unsafe fn root_slots(&self) -> Vec<NonNull<DirectObjPtr<T>>> {
+        let unrooted = self.unrooted.lock();
+        let mut live = self.live_slots();
+        live.retain(|slot| !unrooted.contains(&slot.cast()));
+        live
+    }
+
+    /// Release one root of each slot. Slots stop being roots once every root held on them has been
+    /// released. The slots remain valid for as long as their objects are reachable from the heap,
+    /// and are reclaimed by `reclaim_slots` once their objects die.
+    ///
+    /// # Safety
+    /// Items in the iterator must be live slots provided by this RefTable, and each must still
+    /// hold the root being released.
+    pub unsafe fn unroot_slots<I: Iterator<Item = NonNull<DirectObjPtr<T>>>>(&self, slots: I) {
+        let mut unrooted = self.unrooted.lock();
+        let mut extra_roots = self.extra_roots.lock();
+
+        for slot in slots.map(NonNull::cast) {
+            match extra_roots.entry(slot) {
+                Entry::Occupied(entry) if *entry.get() == 1 => {
+                    entry.remove();
+                }
+                Entry::Occupied(mut entry) => *entry.get_mut() -= 1,
+                Entry::Vacant(_) => {
+                    unrooted.insert(slot);
+                }
+            }
+        }
+    }
+
+    /// Add a root to a slot, which must later be released by `unroot_slots`. A slot which is no
+    /// longer a root becomes one again.
+    ///
+    /// # Safety
+    /// The slot must be a live slot provided by this RefTable.
+    pub unsafe fn root_slot(&self, slot: NonNull<DirectObjPtr<T>>) {
+        let mut unrooted = self.unrooted.lock();
+
+        if !unrooted.remove(&slot.cast()) {
+            *self.extra_roots.lock().entry(slot.cast()).or_insert(0) += 1;
+        }
+    }
+
+    /// Every live slot which is no longer a root
+    pub fn unrooted_slots(&self) -> Vec<NonNull<DirectObjPtr<T>>> {
+        self.unrooted
+            .lock()
+            .iter()
+            .map(|slot| slot.cast())
+            .collect()
+    }
+
+    /// Free every unrooted slot whose object has died. Slots are returned to the chain of empty
+    /// slots one block at a time, and blocks left completely empty are released once the number
+    /// of live slots falls well below its peak. Returns the slots which were freed.
+    ///
+    /// # Safety
+    /// `is_dead` must only return true for slots whose object can no longer be reached. No other
+    /// thread may claim or free slots while slots are being reclaimed.
+    pub unsafe fn reclaim_slots<F>(&self, is_dead: F) -> Vec<NonNull<DirectObjPtr<T>>>
+    where
+        F: Fn(NonNull<DirectObjPtr<T>>) -> bool,
+    {
+        let dead = {
+            let mut unrooted = self.unrooted.lock();
+            let dead = unrooted
+                .iter()
+                .copied()
+                .filter(|slot| is_dead(slot.cast()))
+                .collect::<Vec<_>>();
+
+            for slot in &dead {
+                unrooted.remove(slot);
+            }
+            dead
+        };
+
+        let mut batches = {
+            let blocks = self.blocks.lock();
+            let mut batches = vec![Vec::new(); blocks.len()];
+
+            for slot in &dead {
+                let (idx, _) = self.find_block(*slot).unwrap();
+                batches[idx].push(slot.cast());
+            }
+            batches
+        };
+
+        for batch in &mut batches {
+            self.free_slots(batch.drain(..));
+        }
+
+        self.release_empty_blocks();
+        dead.into_iter().map(NonNull::cast).collect()
+    }
+
+    /// Release every block which holds no live slots once the number of live slots has fallen
+    /// below half of its peak. At least one block is always kept. The chain of empty slots is
+    /// rebuilt from the remaining blocks.
+    ///
+    /// # Safety
+    /// No other thread may claim or free slots while blocks are being released.
+    unsafe fn release_empty_blocks(&self) {
+        let live = self.live.load(Ordering::SeqCst);
+        if live > self.peak.load(Ordering::SeqCst) / 2 {
+            return;
+        }
+
+        let mut blocks = self.blocks.lock();
+
+        // One empty block is kept when no other block has a free slot, so the rebuilt chain of
+        // empty slots is never empty
+        let mut spare = !blocks
+            .iter()
+            .filter(|block| !block.is_empty())
+            .any(|block| block.slots().any(|(_, free)| free));
+        let (kept, released) = blocks.drain(..).partition::<Vec<_>, _>(|block| {
+            !block.is_empty() || std::mem::replace(&mut spare, false)
+        });
+        *blocks = kept;
+
+        if released.is_empty() {
+            return;
+        }
+        self.publish(&blocks, released);
+
+        let chain = blocks
+            .iter()
+            .flat_map(|block| block.slots())
+            .filter(|(_, free)| *free)
+            .map(|(slot, _)| slot)
+            .collect::<Vec<_>>();
+
+        for pair in chain.windows(2) {
+            pair[0].as_ptr().write(ObjectOrNextEmpty {
+                next_empty: Some(pair[1]),
+            });
+        }
+        chain
+            .last()
+            .unwrap()
+            .as_ptr()
+            .write(ObjectOrNextEmpty { next_empty: None });
+
+        self.empty.store(chain[0].as_ptr(), Ordering::SeqCst);
+        self.peak.store(live, Ordering::SeqCst);
+    }
+
+    /// The number of blocks currently held by this table
+    pub fn block_count(&self) -> usize {
+        self.blocks.lock().len()
+    }
+
+    /// Claim a slot from a thread's cache, refilling the cache with up to a block of slots from
+    /// the shared chain when it runs out. Claims which hit the cache do not touch any shared
+    /// state.
+    pub fn claim_cached(&self, cache: &mut SlotCache) -> OpenRefSlot<T> {
+        if cache.is_empty() {
+            self.refill_cache(cache, self.block_size);
+        }
+
+        OpenRefSlot {
+            wrapped: cache.slots.pop().unwrap().cast(),
+        }
+    }
+
+    /// Move up to `count` slots off the chain of empty slots and into a cache using a single
+    /// compare exchange. The table is grown if the chain is empty. Safe from the ABA problem for
+    /// the same reasons as `claim_slot`, since the slots following the head can not change while
+    /// pops are serialized.
+    pub fn refill_cache(&self, cache: &mut SlotCache, count: usize) {
+        let mut blocks = self.blocks.lock();
+
+        loop {
+            let head = self.empty.load(Ordering::SeqCst);
+            let mut taken = Vec::with_capacity(count);
+            let mut rest = head;
+
+            // The final slot on the chain is never handed out
+            while taken.len() < count {
+                match unsafe { (*rest).next_empty } {
+                    Some(next) => {
+                        taken.push(rest);
+                        rest = next.as_ptr();
+                    }
+                    None => break,
+                }
+            }
+
+            if taken.is_empty() {
+                self.add_block(&mut blocks);
+                continue;
+            }
+
+            if self
+                .empty
+                .compare_exchange(head, rest, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                let live = self.live.fetch_add(taken.len(), Ordering::SeqCst) + taken.len();
+                self.peak.fetch_max(live, Ordering::SeqCst);
+
+                for slot in &taken {
+                    let slot = NonNull::new(*slot).unwrap();
+                    if let Some((_, block)) = unsafe { self.find_block(slot) } {
+                        block.set_free(slot, false);
+                    }
+                }
+
+                // Hand the slots out in the order they appeared on the chain
+                let taken = taken.into_iter().rev();
+                cache
+                    .slots
+                    .extend(taken.map(|slot| NonNull::new(slot).unwrap().cast()));
+                return;
+            }
+        }
+    }
+
+    /// Return every slot held by a cache to the chain of empty slots in a single compare exchange
+    ///
+    /// # Safety
+    /// The cache must have been filled by this table.
+    pub unsafe fn flush_cache(&self, cache: &mut SlotCache) {
+        self.free_slots(cache.slots.drain(..).map(NonNull::cast));
+    }
+
+    /// Pop a slot off the chain of empty slots, growing the table if the chain runs out.
+    ///
+    /// Pops are serialized by the blocks lock while pushes from `free_slots` remain lock-free. This
+    /// rules out the ABA problem of a Treiber stack: the head observed by a pop can not be popped
+    /// and pushed back by another thread before the compare exchange, and pushes never modify a
+    /// slot which is already on the chain, so the `next_empty` read from the head stays valid for
+    /// as long as the head is unchanged.
     pub fn claim_slot(&self) -> OpenRefSlot<T> {
-        // Loop until we successfully update the empty index
+        let mut blocks = self.blocks.lock();
+
         loop {
             let current = self.empty.load(Ordering::SeqCst);
 
             match unsafe { (*current).next_empty } {
                 Some(next) => {
+                    // May only fail if a push has replaced the head in the meantime
                     if self
                         .empty
                         .compare_exchange(
@@ -152,23 +651,27 @@ impl<T> RefTable<T> {
                         )
                         .is_ok()
                     {
-                        return OpenRefSlot {
-                            wrapped: NonNull::new(current).unwrap(),
-                        };
+                        let live = self.live.fetch_add(1, Ordering::SeqCst) + 1;
+                        self.peak.fetch_max(live, Ordering::SeqCst);
+
+                        let wrapped = NonNull::new(current).unwrap();
+                        if let Some((_, block)) = unsafe { self.find_block(wrapped) } {
+                            block.set_free(wrapped, false);
+                        }
+                        return OpenRefSlot { wrapped };
                     }
                 }
-                None => self.attempt_add_new_ref_block(),
+                None => self.add_block(&mut blocks),
             }
         }
     }
 
-    fn attempt_add_new_ref_block(&self) {
-        let mut guard = match self.blocks.try_lock() {
-            Some(guard) => guard,
-            None => return,
-        };
-
-        let mut new_block = RefTableBlock::new();
+    /// Add a new block to the front of the chain of empty slots, keeping the blocks sorted by
+    /// address
+    fn add_block(&self, blocks: &mut MutexGuard<'_, Vec<Pin<Box<RefTableBlock<T>>>>>) {
+        let mut new_block = Box::pin(RefTableBlock::new(self.block_size));
+        #[cfg(feature = "slot_generations")]
+        new_block.set_generation(self.next_generation());
 
         loop {
             let previous = self.empty.load(Ordering::SeqCst);
@@ -176,15 +679,176 @@ impl<T> RefTable<T> {
 
             let new_root = new_block.add_to_chain(root);
 
-            // This should not fail, but loop just in case
+            // Fails if a concurrent push replaced the head
             if self
                 .empty
                 .compare_exchange(previous, new_root, Ordering::SeqCst, Ordering::SeqCst)
                 .is_ok()
             {
-                guard.push(new_block);
+                let idx = blocks.partition_point(|block| block.start() < new_block.start());
+                blocks.insert(idx, new_block);
+                self.publish(blocks, Vec::new());
                 return;
             }
         }
     }
 }
+
+impl<T: ?Sized> Drop for RefTable<T> {
+    fn drop(&mut self) {
+        // Safety: The index was created by a Box, and no other thread can be searching it
+        unsafe { drop(Box::from_raw(self.index.load(Ordering::SeqCst))) };
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn reclaimed_slots_release_empty_blocks() {
+    let table = RefTable::<u64>::with_block_size(4);
+    let mut value = 0u64;
+
+    let slots = (0..12)
+        .map(|_| table.claim_slot().assign(NonNull::from(&mut value)))
+        .collect::<Vec<_>>();
+    let blocks = table.block_count();
+    assert!(blocks >= 4);
+
+    unsafe {
+        table.unroot_slots(slots[1..].iter().copied());
+        assert_eq!(table.root_slots(), [slots[0]]);
+
+        assert_eq!(table.reclaim_slots(|_| true).len(), 11);
+        assert_eq!(table.live_slots(), [slots[0]]);
+    }
+    assert!(table.block_count() < blocks);
+
+    // The rebuilt chain of empty slots can still be claimed from
+    let more = (0..8)
+        .map(|_| table.claim_slot().assign(NonNull::from(&mut value)))
+        .collect::<Vec<_>>();
+    assert_eq!(unsafe { table.live_slots() }.len(), 1 + more.len());
+}
+
+#[test]
+#[cfg(test)]
+fn releasing_blocks_keeps_a_free_slot_when_every_other_block_is_full() {
+    let table = RefTable::<u64>::with_block_size(4);
+    let mut value = 0u64;
+
+    let slots = (0..19)
+        .map(|_| table.claim_slot().assign(NonNull::from(&mut value)))
+        .collect::<Vec<_>>();
+
+    // Each new block is linked in front of the last slot of the first block, which ends the
+    // chain. Keeping only the two full blocks after it leaves no free slots in the kept blocks.
+    unsafe {
+        table.unroot_slots(slots[..3].iter().chain(&slots[11..]).copied());
+        assert_eq!(table.reclaim_slots(|_| true).len(), 11);
+    }
+    assert_eq!(table.block_count(), 3);
+
+    let more = (0..8)
+        .map(|_| table.claim_slot().assign(NonNull::from(&mut value)))
+        .collect::<Vec<_>>();
+    assert_eq!(unsafe { table.live_slots() }.len(), 8 + more.len());
+}
+
+#[test]
+#[cfg(test)]
+fn caches_are_refilled_and_flushed_in_batches() {
+    let table = RefTable::<u64>::with_block_size(8);
+    let mut cache = SlotCache::default();
+    let mut value = 0u64;
+
+    let first = table
+        .claim_cached(&mut cache)
+        .assign(NonNull::from(&mut value));
+    // The last slot of the chain is never handed out
+    assert_eq!(cache.len(), 6);
+
+    // Slots claimed by other threads never come out of the cache
+    let other = table.claim_slot().assign(NonNull::from(&mut value));
+    assert_ne!(other, first);
+    assert_eq!(table.block_count(), 2);
+
+    unsafe {
+        assert_eq!(table.live_slots().len(), 8);
+        table.flush_cache(&mut cache);
+        assert_eq!(table.live_slots(), [first, other]);
+    }
+}
+
+#[test]
+#[cfg(loom)]
+fn loom_concurrent_claims_and_frees_never_share_slots() {
+    use loom::sync::Arc;
+    use loom::thread;
+
+    static VALUE: u64 = 0;
+
+    fn claim(table: &RefTable<u64>) -> usize {
+        table.claim_slot().assign(NonNull::from(&VALUE)).as_ptr() as usize
+    }
+
+    loom::model(|| {
+        let table = Arc::new(RefTable::<u64>::with_block_size(8));
+
+        // Pop two slots and push the first back, which is the interleaving that lets an untagged
+        // Treiber stack hand out the second slot a second time.
+        let popping = thread::spawn({
+            let table = Arc::clone(&table);
+            move || {
+                let first = claim(&table);
+                let second = claim(&table);
+
+                let slot = NonNull::new(first as *mut DirectObjPtr<u64>).unwrap();
+                unsafe { table.free_slots(std::iter::once(slot)) };
+                second
+            }
+        });
+
+        let racing = thread::spawn({
+            let table = Arc::clone(&table);
+            move || claim(&table)
+        });
+
+        let mut claimed = vec![popping.join().unwrap(), racing.join().unwrap()];
+        claimed.push(claim(&table));
+        claimed.sort_unstable();
+        claimed.dedup();
+        assert_eq!(claimed.len(), 3);
+
+        let mut live = unsafe { table.live_slots() }
+            .into_iter()
+            .map(|slot| slot.as_ptr() as usize)
+            .collect::<Vec<_>>();
+        live.sort_unstable();
+        assert_eq!(live, claimed);
+    });
+}
+
+#[test]
+#[cfg(all(loom, feature = "slot_generations"))]
+fn loom_generations_are_read_while_blocks_are_added() {
+    use loom::sync::Arc;
+    use loom::thread;
+
+    static VALUE: u64 = 0;
+
+    loom::model(|| {
+        let table = Arc::new(RefTable::<u64>::with_block_size(2));
+        let first = table.claim_slot().assign(NonNull::from(&VALUE));
+
+        // The first block has no slots left, so the next claim replaces the index
+        let growing = thread::spawn({
+            let table = Arc::clone(&table);
+            move || {
+                table.claim_slot();
+            }
+        });
+
+        assert!(table.generation(first).is_some());
+        growing.join().unwrap();
+        assert!(table.generation(first).is_some());
+    });
+}
@@ -0,0 +1,272 @@
+//! Soft and phantom references along with queues for cleared references, following the semantics
+//! of `java.lang.ref`.
+//!
+//! A `SoftGcPtr` keeps its object alive until the collector runs short on memory. During a major
+//! collection, objects which are only reachable through soft references are kept if they were used
+//! recently, where the allowed idle time grows with the amount of free heap. A `PhantomGcPtr` never
+//! hands out its object, and only serves to report through its queue that the object has been
+//! collected. Cleared references of every strength are pushed onto the `ReferenceQueue` they were
+//! created with.
+
+use crate::alloc::ThreadAllocator;
+use crate::handle::{HandleScope, Root};
+use crate::ptr::{gc_ptr, GcPtr, Handle};
+use crate::trace::Trace;
+use crate::weak::{reference_impls, Strength, WeakCell, WeakGcPtr};
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// The number of milliseconds since the clock used to age soft references was started
+pub(crate) fn now_millis() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// A reference to an object which is kept alive until the heap runs low on memory. Each call to
+/// `upgrade` counts as a use of the object, and objects which have gone unused for longer are
+/// cleared first.
+pub struct SoftGcPtr<T: ?Sized> {
+    cell: Arc<WeakCell>,
+    _phantom: PhantomData<GcPtr<T>>,
+}
+
+impl<T: ?Sized> SoftGcPtr<T> {
+    /// Create a soft pointer to an object allocated by the allocator's vm
+    pub fn new<U: Trace, H: Handle<T>>(allocator: &ThreadAllocator<'_, U>, ptr: H) -> Self {
+        let cell = allocator
+            .vm()
+            .weak_refs()
+            .register(gc_ptr(&ptr).slot(), Strength::Soft, None);
+        Self::from_cell(cell)
+    }
+
+    /// Create a soft pointer which is pushed onto `queue` once it has been cleared
+    pub fn with_queue<U: Trace, H: Handle<T>>(
+        allocator: &ThreadAllocator<'_, U>,
+        ptr: H,
+        queue: &ReferenceQueue<T>,
+    ) -> Self {
+        let cell = allocator.vm().weak_refs().register(
+            gc_ptr(&ptr).slot(),
+            Strength::Soft,
+            Some(queue.inner()),
+        );
+        Self::from_cell(cell)
+    }
+
+    fn from_cell(cell: Arc<WeakCell>) -> Self {
+        SoftGcPtr {
+            cell,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Check if the object has been collected
+    pub fn is_cleared(&self) -> bool {
+        self.cell.slot().is_none()
+    }
+}
+
+impl<T: Trace> SoftGcPtr<T> {
+    /// Get a root for the object and mark it as recently used, or None if the object has been
+    /// collected. As with `WeakGcPtr::upgrade`, the root lasts until the end of `scope`.
+    ///
+    /// # Panics
+    /// Panics if the soft pointer was created by a different VM than the scope.
+    pub fn upgrade<'s>(&self, scope: &'s HandleScope<'_, '_, T>) -> Option<Root<'s, T>> {
+        let root = scope.root(|| self.cell.slot())?;
+        self.cell.touch();
+        Some(root)
+    }
+}
+
+/// A reference which never hands out its object. Once the object has been collected, the
+/// reference is cleared and pushed onto its queue so any resources tied to it can be released.
+pub struct PhantomGcPtr<T: ?Sized> {
+    cell: Arc<WeakCell>,
+    _phantom: PhantomData<GcPtr<T>>,
+}
+
+impl<T: ?Sized> PhantomGcPtr<T> {
+    /// Create a phantom pointer which is pushed onto `queue` once its object has been collected
+    pub fn new<U: Trace, H: Handle<T>>(
+        allocator: &ThreadAllocator<'_, U>,
+        ptr: H,
+        queue: &ReferenceQueue<T>,
+    ) -> Self {
+        let cell = allocator.vm().weak_refs().register(
+            gc_ptr(&ptr).slot(),
+            Strength::Phantom,
+            Some(queue.inner()),
+        );
+        Self::from_cell(cell)
+    }
+
+    fn from_cell(cell: Arc<WeakCell>) -> Self {
+        PhantomGcPtr {
+            cell,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Check if the object has been collected
+    pub fn is_cleared(&self) -> bool {
+        self.cell.slot().is_none()
+    }
+}
+
+reference_impls!(SoftGcPtr, PhantomGcPtr);
+
+/// A reference which was pushed onto a `ReferenceQueue` after being cleared
+pub enum Reference<T: ?Sized> {
+    Soft(SoftGcPtr<T>),
+    Weak(WeakGcPtr<T>),
+    Phantom(PhantomGcPtr<T>),
+}
+
+impl<T: ?Sized> PartialEq for Reference<T> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Reference::Soft(a), Reference::Soft(b)) => a == b,
+            (Reference::Weak(a), Reference::Weak(b)) => a == b,
+            (Reference::Phantom(a), Reference::Phantom(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl<T: ?Sized> Eq for Reference<T> {}
+
+/// The references which have been cleared but not yet taken from a queue
+#[derive(Debug, Default)]
+pub(crate) struct QueueInner {
+    cleared: Mutex<VecDeque<Arc<WeakCell>>>,
+    ready: Condvar,
+}
+
+impl QueueInner {
+    pub(crate) fn push(&self, cell: Arc<WeakCell>) {
+        self.cleared.lock().push_back(cell);
+        self.ready.notify_all();
+    }
+}
+
+/// Receives references once the collector has cleared them. A single queue may be shared by soft,
+/// weak and phantom references to objects of the same type.
+pub struct ReferenceQueue<T: ?Sized> {
+    inner: Arc<QueueInner>,
+    _phantom: PhantomData<GcPtr<T>>,
+}
+
+impl<T: ?Sized> Default for ReferenceQueue<T> {
+    fn default() -> Self {
+        ReferenceQueue {
+            inner: Arc::default(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> ReferenceQueue<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn inner(&self) -> Arc<QueueInner> {
+        self.inner.clone()
+    }
+
+    fn wrap(cell: Arc<WeakCell>) -> Reference<T> {
+        match cell.strength {
+            Strength::Soft => Reference::Soft(SoftGcPtr::from_cell(cell)),
+            Strength::Weak => Reference::Weak(WeakGcPtr::from_cell(cell)),
+            Strength::Phantom => Reference::Phantom(PhantomGcPtr::from_cell(cell)),
+        }
+    }
+
+    /// Take the next cleared reference without waiting
+    pub fn poll(&self) -> Option<Reference<T>> {
+        self.inner.cleared.lock().pop_front().map(Self::wrap)
+    }
+
+    /// Take the next cleared reference, waiting up to `timeout` for one to be enqueued
+    pub fn remove(&self, timeout: Duration) -> Option<Reference<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut cleared = self.inner.cleared.lock();
+
+        while cleared.is_empty() {
+            if self
+                .inner
+                .ready
+                .wait_until(&mut cleared, deadline)
+                .timed_out()
+            {
+                break;
+            }
+        }
+
+        cleared.pop_front().map(Self::wrap)
+    }
+
+    /// The number of references waiting to be taken from the queue
+    pub fn len(&self) -> usize {
+        self.inner.cleared.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.cleared.lock().is_empty()
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn cleared_references_are_enqueued() {
+    use crate::alloc::VirtualMachine;
+    use std::thread;
+
+    let vm = VirtualMachine::<u64>::test_builder()
+        .old_size(1 << 20)
+        .max_heap_size(4 << 20)
+        .soft_ref_lru_ms_per_mb(20)
+        .build();
+    let allocator = vm.attach_thread();
+    let queue = ReferenceQueue::new();
+
+    let (soft, weak, phantom) = {
+        let scope = allocator.handle_scope();
+        let soft = SoftGcPtr::with_queue(&allocator, scope.allocate(1).unwrap(), &queue);
+        let weak = WeakGcPtr::with_queue(&allocator, scope.allocate(2).unwrap(), &queue);
+        let phantom = PhantomGcPtr::new(&allocator, scope.allocate(3).unwrap(), &queue);
+        (soft, weak, phantom)
+    };
+
+    // Only major collections clear soft references, and only once the object has gone unused
+    // for longer than 20ms for each of the 3MB of free heap
+    vm.collect_minor();
+    assert_eq!(queue.len(), 2);
+    assert!(weak.is_cleared());
+    assert!(phantom.is_cleared());
+    {
+        let scope = allocator.handle_scope();
+        let root = soft.upgrade(&scope).unwrap();
+        assert_eq!(unsafe { *root.direct_ptr() }, 1);
+    }
+
+    vm.collect_major(false);
+    assert!(!soft.is_cleared());
+
+    thread::sleep(Duration::from_millis(100));
+    vm.collect_major(false);
+    assert!(soft.is_cleared());
+
+    let cleared = std::iter::from_fn(|| queue.poll()).collect::<Vec<_>>();
+    assert_eq!(cleared.len(), 3);
+    assert!(cleared.contains(&Reference::Soft(soft)));
+    assert!(cleared.contains(&Reference::Weak(weak)));
+    assert!(cleared.contains(&Reference::Phantom(phantom)));
+    assert!(queue.remove(Duration::from_millis(1)).is_none());
+}
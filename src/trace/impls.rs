@@ -0,0 +1,222 @@
+//! Implementations of `Trace` for primitives and standard library types
+
+use super::{Trace, TraceContext};
+use crate::ptr::GcPtr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::Arc;
+
+impl<T: ?Sized> Trace for GcPtr<T> {
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        cxt.visit(self);
+    }
+}
+
+macro_rules! leaf_trace {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl Trace for $ty {
+                const IS_LEAF: bool = true;
+
+                #[inline(always)]
+                unsafe fn trace(&self, _: &mut TraceContext) {}
+            }
+        )*
+    };
+}
+
+leaf_trace! {
+    (), bool, char, str, String,
+    u8, u16, u32, u64, u128, usize,
+    i8, i16, i32, i64, i128, isize,
+    f32, f64,
+}
+
+impl<T: ?Sized> Trace for PhantomData<T> {
+    const IS_LEAF: bool = true;
+
+    #[inline(always)]
+    unsafe fn trace(&self, _: &mut TraceContext) {}
+}
+
+/// Containers which trace each of their elements in turn
+macro_rules! iter_trace {
+    ($($ty:ident),* $(,)?) => {
+        $(
+            impl<T: Trace> Trace for $ty<T> {
+                const IS_LEAF: bool = T::IS_LEAF;
+
+                unsafe fn trace(&self, cxt: &mut TraceContext) {
+                    if !Self::IS_LEAF {
+                        self.iter().for_each(|elem| elem.trace(cxt));
+                    }
+                }
+            }
+        )*
+    };
+}
+
+iter_trace!(Vec, VecDeque, BTreeSet);
+
+impl<T: Trace> Trace for [T] {
+    const IS_LEAF: bool = T::IS_LEAF;
+
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        if !Self::IS_LEAF {
+            self.iter().for_each(|elem| elem.trace(cxt));
+        }
+    }
+}
+
+impl<T: Trace, const N: usize> Trace for [T; N] {
+    const IS_LEAF: bool = T::IS_LEAF;
+
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        self[..].trace(cxt);
+    }
+}
+
+impl<T: Trace, S> Trace for HashSet<T, S> {
+    const IS_LEAF: bool = T::IS_LEAF;
+
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        if !Self::IS_LEAF {
+            self.iter().for_each(|elem| elem.trace(cxt));
+        }
+    }
+}
+
+impl<K: Trace, V: Trace, S> Trace for HashMap<K, V, S> {
+    const IS_LEAF: bool = K::IS_LEAF && V::IS_LEAF;
+
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        if !Self::IS_LEAF {
+            for (key, value) in self {
+                key.trace(cxt);
+                value.trace(cxt);
+            }
+        }
+    }
+}
+
+impl<K: Trace, V: Trace> Trace for BTreeMap<K, V> {
+    const IS_LEAF: bool = K::IS_LEAF && V::IS_LEAF;
+
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        if !Self::IS_LEAF {
+            for (key, value) in self {
+                key.trace(cxt);
+                value.trace(cxt);
+            }
+        }
+    }
+}
+
+/// Pointers which trace the value they point to
+macro_rules! deref_trace {
+    ($($ty:ident),* $(,)?) => {
+        $(
+            impl<T: ?Sized + Trace> Trace for $ty<T> {
+                const IS_LEAF: bool = T::IS_LEAF;
+
+                unsafe fn trace(&self, cxt: &mut TraceContext) {
+                    T::trace(self, cxt);
+                }
+            }
+        )*
+    };
+}
+
+deref_trace!(Box, Rc, Arc);
+
+impl<T: Trace> Trace for Option<T> {
+    const IS_LEAF: bool = T::IS_LEAF;
+
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        if let Some(value) = self {
+            value.trace(cxt);
+        }
+    }
+}
+
+impl<T: Trace, E: Trace> Trace for Result<T, E> {
+    const IS_LEAF: bool = T::IS_LEAF && E::IS_LEAF;
+
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        match self {
+            Ok(value) => value.trace(cxt),
+            Err(err) => err.trace(cxt),
+        }
+    }
+}
+
+macro_rules! tuple_trace {
+    ($(($($name:ident),+))*) => {
+        $(
+            impl<$($name: Trace),+> Trace for ($($name,)+) {
+                const IS_LEAF: bool = $($name::IS_LEAF)&&+;
+
+                #[allow(non_snake_case)]
+                unsafe fn trace(&self, cxt: &mut TraceContext) {
+                    let ($($name,)+) = self;
+                    $($name.trace(cxt);)+
+                }
+            }
+        )*
+    };
+}
+
+tuple_trace! {
+    (A)
+    (A, B)
+    (A, B, C)
+    (A, B, C, D)
+    (A, B, C, D, E)
+    (A, B, C, D, E, F)
+    (A, B, C, D, E, F, G)
+    (A, B, C, D, E, F, G, H)
+    (A, B, C, D, E, F, G, H, I)
+    (A, B, C, D, E, F, G, H, I, J)
+    (A, B, C, D, E, F, G, H, I, J, K)
+    (A, B, C, D, E, F, G, H, I, J, K, L)
+}
+
+#[test]
+#[cfg(test)]
+fn containers_trace_their_elements() {
+    use std::ptr::NonNull;
+
+    let mut objects = [1u64, 2, 3];
+    let mut slots = objects
+        .each_mut()
+        .map(|object| NonNull::from(object).cast::<()>());
+    let [a, b, c] = slots
+        .each_mut()
+        .map(|slot| unsafe { GcPtr::<u64>::from_slot(NonNull::from(slot).cast()) });
+
+    let mut map = HashMap::new();
+    map.insert(String::from("b"), b);
+
+    let value = (
+        vec![Some(a)],
+        Box::new(map),
+        Rc::new([Ok::<_, ()>(c)]),
+        BTreeMap::from([(0u32, String::from("leaf"))]),
+    );
+
+    let mut cxt = TraceContext::default();
+    unsafe { value.trace(&mut cxt) };
+
+    let mut visited = Vec::new();
+    while let Some(object) = cxt.pop_mark() {
+        visited.push(unsafe { *object.cast::<u64>().as_ref() });
+    }
+    assert_eq!(visited, [3, 2, 1]);
+
+    const {
+        assert!(<Vec<Option<(u8, String)>>>::IS_LEAF);
+        assert!(!<HashMap<u32, GcPtr<u64>>>::IS_LEAF);
+        assert!(!<(u8, Arc<GcPtr<u64>>)>::IS_LEAF);
+    }
+}
@@ -0,0 +1,518 @@
+use crate::mark::{MarkWord, TestMark};
+use crate::ptr::{DirectObjUnknown, GcPtr};
+use std::alloc::Layout;
+use std::collections::VecDeque;
+use std::mem::{align_of, size_of};
+use std::ptr::NonNull;
+
+#[cfg(feature = "derive")]
+pub use generational_gc_derive::Trace;
+
+mod impls;
+
+/// The phase of collection a trace is being performed for. This decides where the edges reported
+/// by objects end up.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TracePhase {
+    /// Edges are pushed onto the mark stack so the objects they point to can be marked in place
+    Mark,
+    /// Edges are pushed onto the copy queue so the objects they point to can be evacuated and the
+    /// references to them updated
+    Copy,
+}
+
+/// An outgoing edge of an object which still needs to be processed by the collector
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Edge {
+    /// A slot in the reference table. Objects reached through a slot may be moved by updating the
+    /// slot.
+    Slot(NonNull<DirectObjUnknown>),
+    /// A direct pointer to an object. The collector has no way of updating the pointer, so objects
+    /// reached this way must be large objects, which are never moved.
+    Object(DirectObjUnknown),
+}
+
+impl Edge {
+    /// Get the object this edge currently points to
+    ///
+    /// # Safety
+    /// If this edge is a slot, it must still be assigned to an object.
+    pub unsafe fn object(self) -> DirectObjUnknown {
+        match self {
+            Edge::Slot(slot) => slot.as_ptr().read(),
+            Edge::Object(object) => object,
+        }
+    }
+}
+
+/// Records the outgoing edges reported by objects as they are traced so the collector can work
+/// through them without recursing.
+///
+/// There is no variant for plain weak edges. A `WeakGcPtr` registers its slot with the VM when it
+/// is created, so the collector clears it after every collection whether or not the object holding
+/// it is traced, including weak pointers held off the heap. Reporting the same slot again while
+/// tracing would only duplicate that work. Weak references whose targets depend on another object,
+/// such as the values of a `GcWeakKeyMap`, are reported through `visit_ephemeron`.
+pub struct TraceContext {
+    phase: TracePhase,
+    mark_stack: Vec<DirectObjUnknown>,
+    copy_queue: VecDeque<Edge>,
+    ephemerons: Vec<(NonNull<DirectObjUnknown>, NonNull<DirectObjUnknown>)>,
+}
+
+impl TraceContext {
+    pub(crate) fn new(phase: TracePhase) -> Self {
+        TraceContext {
+            phase,
+            mark_stack: Vec::new(),
+            copy_queue: VecDeque::new(),
+            ephemerons: Vec::new(),
+        }
+    }
+
+    /// The phase of collection this context is being used for
+    pub fn phase(&self) -> TracePhase {
+        self.phase
+    }
+
+    /// Report a strong reference to another object on the heap
+    pub fn visit<U: ?Sized>(&mut self, ptr: &GcPtr<U>) {
+        self.push_edge(Edge::Slot(ptr.slot()));
+    }
+
+    /// Report a value which should only be kept alive for as long as its key is. While marking,
+    /// the pair is held back until the key is found to be reachable. Minor collections treat the
+    /// value as a strong reference, so dead keys are only found by a major collection.
+    pub fn visit_ephemeron<K: ?Sized, V: ?Sized>(&mut self, key: &GcPtr<K>, value: &GcPtr<V>) {
+        match self.phase {
+            TracePhase::Mark => self.ephemerons.push((key.slot(), value.slot())),
+            TracePhase::Copy => self.visit(value),
+        }
+    }
+
+    /// Report a strong reference held as a direct pointer. Since the collector is unable to
+    /// update the pointer, the object must be one which is never moved.
+    ///
+    /// # Safety
+    /// `object` must point to a live object in the large object space of the same heap as the
+    /// object being traced. Objects in the young generation are moved by minor collections and
+    /// objects in the old generation are moved by compacting major collections, so only large
+    /// objects stay in place. `ThreadAllocator::allocates_large_objects` checks whether a VM
+    /// places its objects in the large object space.
+    pub unsafe fn visit_raw(&mut self, object: DirectObjUnknown) {
+        self.push_edge(Edge::Object(object));
+    }
+
+    pub(crate) fn push_edge(&mut self, edge: Edge) {
+        match self.phase {
+            TracePhase::Mark => self.mark_stack.push(unsafe { edge.object() }),
+            TracePhase::Copy => self.copy_queue.push_back(edge),
+        }
+    }
+
+    /// Take the next object from the mark stack
+    pub fn pop_mark(&mut self) -> Option<DirectObjUnknown> {
+        self.mark_stack.pop()
+    }
+
+    /// Take the next edge from the copy queue. Edges are handed out in the order they were
+    /// reported so objects are evacuated breadth first.
+    pub fn pop_copy(&mut self) -> Option<Edge> {
+        self.copy_queue.pop_front()
+    }
+
+    /// Take the slots of the keys and values of the ephemerons which were reported since the
+    /// last call
+    pub fn take_ephemerons(
+        &mut self,
+    ) -> Vec<(NonNull<DirectObjUnknown>, NonNull<DirectObjUnknown>)> {
+        std::mem::take(&mut self.ephemerons)
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        TraceContext::new(TracePhase::Mark)
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn edges_are_routed_by_phase() {
+    let mut object = 0u64;
+    let direct = NonNull::from(&mut object).cast::<()>();
+    let mut slot = direct;
+    let ptr = unsafe { GcPtr::from_slot(NonNull::from(&mut slot)) };
+
+    let mut mark = TraceContext::new(TracePhase::Mark);
+    mark.visit(&ptr);
+    assert_eq!(mark.pop_mark(), Some(direct));
+    assert_eq!(mark.pop_copy(), None);
+
+    let mut copy = TraceContext::new(TracePhase::Copy);
+    copy.visit(&ptr);
+    unsafe { copy.visit_raw(direct) };
+    assert_eq!(copy.pop_mark(), None);
+    assert_eq!(copy.pop_copy(), Some(Edge::Slot(NonNull::from(&mut slot))));
+    assert_eq!(copy.pop_copy(), Some(Edge::Object(direct)));
+}
+
+/// Describes how objects and their headers are laid out on the heap so the collector can operate
+/// on objects without knowing their types.
+///
+/// # Safety
+/// Implementations must only produce information which is consistent with how objects were
+/// initialized by the matching `HeapObjectSetup` implementation.
+pub unsafe trait HeapObjectLayout {
+    type MarkWord: MarkWord;
+
+    /// Get a reference to the mark word of an unknown object on the heap
+    ///
+    /// # Safety
+    /// `ptr` must point to a live object which was allocated using this layout.
+    unsafe fn mark<'a>(ptr: DirectObjUnknown) -> &'a Self::MarkWord;
+
+    /// Get the layout of an unknown object on the heap by its pointer
+    ///
+    /// # Safety
+    /// `ptr` must point to a live object which was allocated using this layout.
+    unsafe fn layout(ptr: DirectObjUnknown) -> Layout;
+
+    /// Get the offset from the start of the allocation holding an unknown object to its data
+    ///
+    /// # Safety
+    /// `ptr` must point to a live object which was allocated using this layout.
+    unsafe fn data_offset(ptr: DirectObjUnknown) -> usize;
+
+    /// Find the object whose allocation begins at `ptr` when walking a heap region. Only objects
+    /// with an alignment no greater than the heap alignment can be found this way.
+    ///
+    /// # Safety
+    /// `ptr` must be the start of an allocation holding an object or filler of this layout.
+    unsafe fn object_at(ptr: NonNull<u8>) -> DirectObjUnknown;
+
+    /// Overwrite `size` bytes starting at `ptr` with a filler so the memory is skipped when
+    /// walking the heap. Fillers are never traced or dropped.
+    ///
+    /// # Safety
+    /// `ptr` must be aligned to the heap alignment, and the memory must be unused and at least
+    /// `min_filler_size` bytes long.
+    unsafe fn write_filler(ptr: NonNull<u8>, size: usize) -> DirectObjUnknown;
+
+    /// The smallest number of bytes which can be turned into a filler
+    fn min_filler_size() -> usize;
+
+    /// Check if an object is a filler written by `write_filler`
+    ///
+    /// # Safety
+    /// `ptr` must point to an object or filler of this layout.
+    unsafe fn is_filler(ptr: DirectObjUnknown) -> bool;
+
+    /// Invoke the trace function of an unknown object on the heap
+    ///
+    /// # Safety
+    /// `ptr` must point to a live object which was allocated using this layout.
+    unsafe fn trace(ptr: DirectObjUnknown, cxt: &mut TraceContext);
+
+    /// Drop the data of an unknown object on the heap
+    ///
+    /// # Safety
+    /// `ptr` must point to a live object which was allocated using this layout. The object must
+    /// not be used again after it has been dropped. Objects are dropped while the collector holds
+    /// the locks of the space being swept, so their `Drop` implementations must not allocate.
+    #[cfg(feature = "drop_heap")]
+    unsafe fn drop(ptr: DirectObjUnknown);
+}
+
+pub trait HeapObjectSetup<T>: HeapObjectLayout {
+    fn wrap_layout(data_layout: Layout) -> Layout;
+
+    /// Write the object header into freshly allocated memory and return a pointer to the
+    /// uninitialized data of the object.
+    ///
+    /// # Safety
+    /// `ptr` must point to an allocation of at least `layout` which was produced by `wrap_layout`.
+    unsafe fn init_object(ptr: NonNull<u8>, layout: Layout) -> NonNull<T>;
+}
+
+/// Objects which can be stored on the heap.
+///
+/// With the `drop_heap` feature, dead objects are dropped by the collector while it holds the
+/// heap's locks. The `Drop` implementation of a heap object must not allocate on the heap, since
+/// doing so deadlocks the collection.
+pub trait Trace {
+    /// Set for types which can never hold a garbage collected pointer. The collector does not
+    /// trace objects of leaf types and containers skip walking over leaf elements.
+    const IS_LEAF: bool = false;
+
+    /// Report every garbage collected pointer held by this object to the collector.
+    ///
+    /// # Safety
+    /// Must only be called by the collector while the heap is not being mutated.
+    unsafe fn trace(&self, cxt: &mut TraceContext);
+}
+
+/// Implemented for every type which implements `Trace`. The derive macro traces fields through
+/// this trait so a field which can not be traced is reported without listing every type which can.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` does not implement `Trace`",
+    label = "field can not be traced",
+    note = "fields must implement `Trace` unless they are marked with `#[trace(skip)]`"
+)]
+pub trait TracedField {
+    const IS_LEAF: bool;
+
+    /// # Safety
+    /// Same as `Trace::trace`.
+    unsafe fn trace_field(&self, cxt: &mut TraceContext);
+}
+
+#[diagnostic::do_not_recommend]
+impl<T: Trace + ?Sized> TracedField for T {
+    const IS_LEAF: bool = T::IS_LEAF;
+
+    unsafe fn trace_field(&self, cxt: &mut TraceContext) {
+        self.trace(cxt);
+    }
+}
+
+pub struct AnnotatedMixedHeap;
+
+unsafe impl HeapObjectLayout for AnnotatedMixedHeap {
+    type MarkWord = TestMark;
+
+    unsafe fn mark<'a>(ptr: DirectObjUnknown) -> &'a Self::MarkWord {
+        &HeapAnnotation::of(ptr).mark
+    }
+
+    unsafe fn layout(ptr: DirectObjUnknown) -> Layout {
+        HeapAnnotation::of(ptr).layout
+    }
+
+    unsafe fn data_offset(ptr: DirectObjUnknown) -> usize {
+        HeapAnnotation::data_offset(Self::layout(ptr).align())
+    }
+
+    unsafe fn object_at(ptr: NonNull<u8>) -> DirectObjUnknown {
+        let offset = HeapAnnotation::data_offset(align_of::<HeapAnnotation>());
+        NonNull::new_unchecked(ptr.as_ptr().add(offset)).cast()
+    }
+
+    unsafe fn write_filler(ptr: NonNull<u8>, size: usize) -> DirectObjUnknown {
+        debug_assert!(size >= Self::min_filler_size());
+        let layout = Layout::from_size_align_unchecked(size, align_of::<HeapAnnotation>());
+
+        let object = Self::object_at(ptr);
+        object
+            .cast::<HeapAnnotation>()
+            .as_ptr()
+            .sub(1)
+            .write(HeapAnnotation {
+                layout,
+                mark: TestMark::default(),
+                vtable: ObjectVTable::FILLER,
+            });
+        object
+    }
+
+    fn min_filler_size() -> usize {
+        HeapAnnotation::data_offset(align_of::<HeapAnnotation>())
+    }
+
+    unsafe fn is_filler(ptr: DirectObjUnknown) -> bool {
+        HeapAnnotation::of(ptr).vtable.filler
+    }
+
+    unsafe fn trace(ptr: DirectObjUnknown, cxt: &mut TraceContext) {
+        if let Some(trace) = HeapAnnotation::of(ptr).vtable.trace {
+            trace(ptr, cxt);
+        }
+    }
+
+    #[cfg(feature = "drop_heap")]
+    unsafe fn drop(ptr: DirectObjUnknown) {
+        if let Some(drop) = HeapAnnotation::of(ptr).vtable.drop {
+            drop(ptr);
+        }
+    }
+}
+
+impl<T: TypedTrace> HeapObjectSetup<T> for AnnotatedMixedHeap {
+    fn wrap_layout(data_layout: Layout) -> Layout {
+        let align = data_layout.align().max(align_of::<HeapAnnotation>());
+        let size = HeapAnnotation::data_offset(align) + data_layout.size();
+
+        Layout::from_size_align(size, align)
+            .expect("Object is too large to be allocated on the heap")
+            .pad_to_align()
+    }
+
+    unsafe fn init_object(ptr: NonNull<u8>, layout: Layout) -> NonNull<T> {
+        let data = ptr
+            .as_ptr()
+            .add(HeapAnnotation::data_offset(layout.align()));
+
+        data.cast::<HeapAnnotation>().sub(1).write(HeapAnnotation {
+            layout,
+            mark: TestMark::default(),
+            vtable: T::vtable(),
+        });
+
+        NonNull::new_unchecked(data.cast())
+    }
+}
+
+/// Type specific functions used to build the vtable stored alongside each object on the heap.
+///
+/// # Safety
+/// The functions in the vtable must only be used with objects of the implementing type.
+pub unsafe trait TypedTrace {
+    fn vtable() -> ObjectVTable;
+
+    /// # Safety
+    /// `ptr` must point to an object of this type which was allocated in an annotated heap.
+    unsafe fn _trace(ptr: NonNull<()>, cxt: &mut TraceContext);
+
+    /// # Safety
+    /// `ptr` must point to an object of this type which was allocated in an annotated heap. The
+    /// object must not be used again after it has been dropped.
+    unsafe fn _drop(ptr: NonNull<()>);
+}
+
+unsafe impl<T: Trace> TypedTrace for T {
+    fn vtable() -> ObjectVTable {
+        ObjectVTable {
+            filler: false,
+            trace: match T::IS_LEAF {
+                true => None,
+                false => Some(<T as TypedTrace>::_trace),
+            },
+            #[cfg(feature = "drop_heap")]
+            drop: match std::mem::needs_drop::<T>() {
+                true => Some(<T as TypedTrace>::_drop),
+                false => None,
+            },
+        }
+    }
+
+    unsafe fn _trace(ptr: NonNull<()>, cxt: &mut TraceContext) {
+        Trace::trace(ptr.cast::<T>().as_ref(), cxt)
+    }
+
+    unsafe fn _drop(ptr: NonNull<()>) {
+        std::ptr::drop_in_place(ptr.cast::<T>().as_ptr())
+    }
+}
+
+/// The header of an object on an annotated heap. Direct pointers always refer to the data of the
+/// object, and the annotation is always placed directly before it so it can be found without
+/// knowing the type of the object. Objects with a large alignment are padded before the
+/// annotation.
+#[repr(C)]
+struct HeapAnnotation {
+    layout: Layout,
+    mark: TestMark,
+    vtable: ObjectVTable,
+}
+
+impl HeapAnnotation {
+    /// The offset from the start of an allocation to the data of an object with the given
+    /// alignment.
+    const fn data_offset(align: usize) -> usize {
+        let size = size_of::<Self>();
+        (size + align - 1) & !(align - 1)
+    }
+
+    /// Get the annotation of an object from a direct pointer to its data.
+    unsafe fn of<'a>(ptr: DirectObjUnknown) -> &'a Self {
+        &*ptr.cast::<HeapAnnotation>().as_ptr().sub(1)
+    }
+}
+
+#[repr(C)]
+pub struct ObjectVTable {
+    /// Set for fillers covering unused memory
+    filler: bool,
+    /// Leaf objects have no trace function since they never need to be traced
+    trace: Option<unsafe fn(ptr: NonNull<()>, cxt: &mut TraceContext)>,
+    /// Types without drop glue have no drop function since there is nothing to run
+    #[cfg(feature = "drop_heap")]
+    drop: Option<unsafe fn(ptr: NonNull<()>)>,
+}
+
+impl ObjectVTable {
+    const FILLER: ObjectVTable = ObjectVTable {
+        filler: true,
+        trace: None,
+        #[cfg(feature = "drop_heap")]
+        drop: None,
+    };
+}
+
+#[test]
+#[cfg(test)]
+fn derived_trace_visits_fields() {
+    use generational_gc_derive::Trace;
+
+    struct Edge(GcPtr<u64>);
+
+    impl Trace for Edge {
+        unsafe fn trace(&self, cxt: &mut TraceContext) {
+            cxt.visit(&self.0);
+        }
+    }
+
+    struct Opaque;
+
+    #[derive(Trace)]
+    struct Named {
+        first: Edge,
+        #[trace(skip)]
+        _opaque: Opaque,
+        second: Edge,
+    }
+
+    #[derive(Trace)]
+    struct Unit;
+
+    #[derive(Trace)]
+    enum Either<T> {
+        Left(T),
+        Right { inner: T },
+        Neither(#[trace(skip)] Opaque),
+        Empty(Unit),
+    }
+
+    let mut objects = [1u64, 2];
+    let mut slots = objects
+        .each_mut()
+        .map(|object| NonNull::from(object).cast::<()>());
+    let [first, second] = slots
+        .each_mut()
+        .map(|slot| unsafe { GcPtr::from_slot(NonNull::from(slot).cast()) });
+
+    let named = Named {
+        first: Edge(first),
+        _opaque: Opaque,
+        second: Edge(second),
+    };
+    let values = [
+        Either::Left(Edge(second)),
+        Either::Right { inner: Edge(first) },
+        Either::Neither(Opaque),
+        Either::Empty(Unit),
+    ];
+
+    let mut cxt = TraceContext::default();
+    unsafe {
+        named.trace(&mut cxt);
+        values.iter().for_each(|value| value.trace(&mut cxt));
+    }
+
+    let mut visited = Vec::new();
+    while let Some(object) = cxt.pop_mark() {
+        visited.push(unsafe { *object.cast::<u64>().as_ref() });
+    }
+    assert_eq!(visited, [1, 2, 2, 1]);
+}
@@ -0,0 +1,584 @@
+//! Weak references which do not keep their referents alive.
+//!
+//! A `WeakGcPtr` refers to the slot of its object in the reference table, so it follows the object
+//! when it is moved without being traced. Every weak pointer is registered with its VM, and the
+//! collector clears the pointers to an object once the object's slot is reclaimed. The soft and
+//! phantom pointers in `reference` share the same registry.
+//!
+//! An `Ephemeron` pairs a weak key with a value which is only kept alive for as long as the key
+//! is. Ephemerons are resolved by the mark phase, so a value which refers back to its own key does
+//! not keep the key alive. `GcWeakKeyMap` builds an associative table out of them.
+
+use crate::alloc::ThreadAllocator;
+use crate::handle::{HandleScope, Root};
+use crate::ptr::{gc_ptr, DirectObjUnknown, GcPtr, Handle};
+use crate::reference::{now_millis, QueueInner, ReferenceQueue};
+use crate::trace::{Trace, TraceContext};
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ptr::{null_mut, NonNull};
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How strongly a reference holds on to its object
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Strength {
+    /// Kept alive by major collections while the heap has room and the object was used recently
+    Soft,
+    Weak,
+    /// Never hands out the object, only reports when it has been collected
+    Phantom,
+}
+
+/// The slot a reference refers to, shared between every clone of the reference and the collector
+#[derive(Debug)]
+pub(crate) struct WeakCell {
+    /// Null once the object has died
+    slot: AtomicPtr<DirectObjUnknown>,
+    pub(crate) strength: Strength,
+    /// When a soft reference was last used, in milliseconds since the reference clock started
+    last_access: AtomicU64,
+    /// The queue the cell is pushed onto once it has been cleared
+    queue: Option<Arc<QueueInner>>,
+}
+
+impl WeakCell {
+    /// The slot of the object, or None once it has been cleared
+    pub(crate) fn slot(&self) -> Option<NonNull<DirectObjUnknown>> {
+        NonNull::new(self.slot.load(Ordering::SeqCst))
+    }
+
+    /// Record that the object was just used, which delays clearing a soft reference to it
+    pub(crate) fn touch(&self) {
+        self.last_access.store(now_millis(), Ordering::Relaxed);
+    }
+
+    /// The number of milliseconds since the object was last used
+    fn idle_millis(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_access.load(Ordering::Relaxed))
+    }
+}
+
+/// A reference to an object which does not keep it alive. Once the object has been found to be
+/// unreachable, the collector clears the pointer and every clone of it.
+pub struct WeakGcPtr<T: ?Sized> {
+    cell: Arc<WeakCell>,
+    _phantom: PhantomData<GcPtr<T>>,
+}
+
+impl<T: ?Sized> WeakGcPtr<T> {
+    /// Create a weak pointer to an object allocated by the allocator's vm
+    pub fn new<U: Trace, H: Handle<T>>(allocator: &ThreadAllocator<'_, U>, ptr: H) -> Self {
+        let cell = allocator
+            .vm()
+            .weak_refs()
+            .register(gc_ptr(&ptr).slot(), Strength::Weak, None);
+        Self::from_cell(cell)
+    }
+
+    /// Create a weak pointer which is pushed onto `queue` once it has been cleared
+    pub fn with_queue<U: Trace, H: Handle<T>>(
+        allocator: &ThreadAllocator<'_, U>,
+        ptr: H,
+        queue: &ReferenceQueue<T>,
+    ) -> Self {
+        let cell = allocator.vm().weak_refs().register(
+            gc_ptr(&ptr).slot(),
+            Strength::Weak,
+            Some(queue.inner()),
+        );
+        Self::from_cell(cell)
+    }
+
+    pub(crate) fn from_cell(cell: Arc<WeakCell>) -> Self {
+        WeakGcPtr {
+            cell,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Check if the object has been collected
+    pub fn is_cleared(&self) -> bool {
+        self.cell.slot().is_none()
+    }
+}
+
+impl<T: Trace> WeakGcPtr<T> {
+    /// Get a root for the object which lasts until the end of `scope`, or None if the object has
+    /// been collected.
+    ///
+    /// # Panics
+    /// Panics if the weak pointer was created by a different VM than the scope.
+    pub fn upgrade<'s>(&self, scope: &'s HandleScope<'_, '_, T>) -> Option<Root<'s, T>> {
+        // The slot is cleared before it is returned to the reference table
+        scope.root(|| self.cell.slot())
+    }
+}
+
+/// Implements the traits shared by every kind of reference which is backed by a `WeakCell`.
+/// Deriving them would place bounds on the type of the referenced object.
+macro_rules! reference_impls {
+    ($($ty:ident),* $(,)?) => {
+        $(
+            impl<T: ?Sized> Clone for $ty<T> {
+                fn clone(&self) -> Self {
+                    $ty {
+                        cell: self.cell.clone(),
+                        _phantom: std::marker::PhantomData,
+                    }
+                }
+            }
+
+            /// References are equal when they are clones of each other
+            impl<T: ?Sized> PartialEq for $ty<T> {
+                fn eq(&self, other: &Self) -> bool {
+                    std::sync::Arc::ptr_eq(&self.cell, &other.cell)
+                }
+            }
+
+            impl<T: ?Sized> Eq for $ty<T> {}
+
+            /// References are not edges, so there is nothing for the collector to follow
+            impl<T: ?Sized> $crate::trace::Trace for $ty<T> {
+                const IS_LEAF: bool = true;
+
+                #[inline(always)]
+                unsafe fn trace(&self, _: &mut $crate::trace::TraceContext) {}
+            }
+        )*
+    };
+}
+
+pub(crate) use reference_impls;
+
+reference_impls!(WeakGcPtr);
+
+/// The key and value of an ephemeron. Both are cleared together once either of them dies.
+#[derive(Debug)]
+struct EphemeronCell {
+    key: AtomicPtr<DirectObjUnknown>,
+    value: AtomicPtr<DirectObjUnknown>,
+}
+
+impl EphemeronCell {
+    fn get(&self) -> Option<(NonNull<DirectObjUnknown>, NonNull<DirectObjUnknown>)> {
+        let key = NonNull::new(self.key.load(Ordering::SeqCst))?;
+        let value = NonNull::new(self.value.load(Ordering::SeqCst))?;
+        Some((key, value))
+    }
+}
+
+/// A pair which holds on to its value only for as long as its key is reachable through some other
+/// path. The key is not kept alive by the ephemeron or by its value. The ephemeron must be traced
+/// as part of an object on the heap for its value to be kept alive.
+pub struct Ephemeron<K: ?Sized, V: ?Sized> {
+    cell: Arc<EphemeronCell>,
+    _phantom: PhantomData<(GcPtr<K>, GcPtr<V>)>,
+}
+
+impl<K: ?Sized, V: ?Sized> Clone for Ephemeron<K, V> {
+    fn clone(&self) -> Self {
+        Ephemeron {
+            cell: self.cell.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<K: ?Sized, V: ?Sized> Ephemeron<K, V> {
+    /// Create an ephemeron between objects allocated by the allocator's vm
+    pub fn new<U: Trace, HK: Handle<K>, HV: Handle<V>>(
+        allocator: &ThreadAllocator<'_, U>,
+        key: HK,
+        value: HV,
+    ) -> Self {
+        Ephemeron {
+            cell: allocator
+                .vm()
+                .weak_refs()
+                .register_ephemeron(gc_ptr(&key).slot(), gc_ptr(&value).slot()),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Check if the key has been collected
+    pub fn is_cleared(&self) -> bool {
+        self.cell.get().is_none()
+    }
+
+    /// The key and value, which are not roots
+    fn pointers(&self) -> Option<(GcPtr<K>, GcPtr<V>)> {
+        let (key, value) = self.cell.get()?;
+
+        // Safety: The slots are cleared before either of them is returned to the reference table
+        unsafe { Some((GcPtr::from_slot(key.cast()), GcPtr::from_slot(value.cast()))) }
+    }
+}
+
+impl<K, V> Ephemeron<K, V> {
+    /// Get roots for the key and value which last until the end of `scope`, or None if the key
+    /// has been collected. The value stays alive for as long as the rooted key does.
+    ///
+    /// # Panics
+    /// Panics if the ephemeron was created by a different VM than the scope.
+    pub fn get<'s, T: Trace>(
+        &self,
+        scope: &'s HandleScope<'_, '_, T>,
+    ) -> Option<(Root<'s, K>, Root<'s, V>)> {
+        let key = scope.root(|| self.cell.get().map(|(key, _)| key))?;
+        let value = scope
+            .root(|| self.cell.get().map(|(_, value)| value))
+            .expect("ephemeron was cleared while its key was rooted");
+        Some((key, value))
+    }
+
+    pub fn key<'s, T: Trace>(&self, scope: &'s HandleScope<'_, '_, T>) -> Option<Root<'s, K>> {
+        scope.root(|| self.cell.get().map(|(key, _)| key))
+    }
+
+    pub fn value<'s, T: Trace>(&self, scope: &'s HandleScope<'_, '_, T>) -> Option<Root<'s, V>> {
+        self.get(scope).map(|(_, value)| value)
+    }
+}
+
+impl<K: ?Sized, V: ?Sized> Trace for Ephemeron<K, V> {
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        if let Some((key, value)) = self.pointers() {
+            cxt.visit_ephemeron(&key, &value);
+        }
+    }
+}
+
+/// A map whose entries are removed once their key dies. Values are kept alive by the map only for
+/// as long as their key is reachable. Keys are compared by identity.
+pub struct GcWeakKeyMap<K: ?Sized, V: ?Sized> {
+    entries: HashMap<NonNull<DirectObjUnknown>, Ephemeron<K, V>>,
+}
+
+impl<K: ?Sized, V: ?Sized> Default for GcWeakKeyMap<K, V> {
+    fn default() -> Self {
+        GcWeakKeyMap {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: ?Sized, V: ?Sized> GcWeakKeyMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Associate a value with a key, returning the entry previously held for the key
+    pub fn insert<U: Trace, HK: Handle<K>, HV: Handle<V>>(
+        &mut self,
+        allocator: &ThreadAllocator<'_, U>,
+        key: HK,
+        value: HV,
+    ) -> Option<Ephemeron<K, V>> {
+        let ephemeron = Ephemeron::new(allocator, key, value);
+        self.entries.insert(gc_ptr(&key).slot(), ephemeron)
+    }
+
+    pub fn remove<H: Handle<K>>(&mut self, key: &H) -> Option<Ephemeron<K, V>> {
+        self.entries.remove(&gc_ptr(key).slot())
+    }
+
+    /// Forget every entry whose key has been collected
+    pub fn remove_cleared(&mut self) {
+        self.entries
+            .retain(|_, ephemeron| ephemeron.cell.get().is_some());
+    }
+
+    /// The number of entries in the map, including entries whose keys were collected since the
+    /// last call to `remove_cleared`
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K, V> GcWeakKeyMap<K, V> {
+    /// Get a root for the value associated with a key which lasts until the end of `scope`
+    pub fn get<'s, H: Handle<K>, T: Trace>(
+        &self,
+        key: &H,
+        scope: &'s HandleScope<'_, '_, T>,
+    ) -> Option<Root<'s, V>> {
+        // A cleared entry may share its slot with a newer object
+        self.entries.get(&gc_ptr(key).slot())?.value(scope)
+    }
+
+    /// Iterate over the entries whose keys are still alive, rooting each key and value within
+    /// `scope` as it is reached
+    pub fn iter<'a, T: Trace>(
+        &'a self,
+        scope: &'a HandleScope<'a, 'a, T>,
+    ) -> impl Iterator<Item = (Root<'a, K>, Root<'a, V>)> + 'a {
+        self.entries
+            .values()
+            .filter_map(move |ephemeron| ephemeron.get(scope))
+    }
+}
+
+impl<K: ?Sized, V: ?Sized> Trace for GcWeakKeyMap<K, V> {
+    unsafe fn trace(&self, cxt: &mut TraceContext) {
+        for ephemeron in self.entries.values() {
+            ephemeron.trace(cxt);
+        }
+    }
+}
+
+/// Every weak, soft and phantom reference and every ephemeron created for a VM
+#[derive(Debug, Default)]
+pub(crate) struct WeakRefs {
+    cells: Mutex<Vec<Arc<WeakCell>>>,
+    ephemerons: Mutex<Vec<Arc<EphemeronCell>>>,
+}
+
+impl WeakRefs {
+    pub(crate) fn register(
+        &self,
+        slot: NonNull<DirectObjUnknown>,
+        strength: Strength,
+        queue: Option<Arc<QueueInner>>,
+    ) -> Arc<WeakCell> {
+        let cell = Arc::new(WeakCell {
+            slot: AtomicPtr::new(slot.as_ptr()),
+            strength,
+            last_access: AtomicU64::new(now_millis()),
+            queue,
+        });
+
+        self.cells.lock().push(cell.clone());
+        cell
+    }
+
+    fn register_ephemeron(
+        &self,
+        key: NonNull<DirectObjUnknown>,
+        value: NonNull<DirectObjUnknown>,
+    ) -> Arc<EphemeronCell> {
+        let cell = Arc::new(EphemeronCell {
+            key: AtomicPtr::new(key.as_ptr()),
+            value: AtomicPtr::new(value.as_ptr()),
+        });
+
+        self.ephemerons.lock().push(cell.clone());
+        cell
+    }
+
+    /// The slots of every object held by a soft reference. Minor collections treat these as roots.
+    pub(crate) fn soft_slots(&self) -> Vec<NonNull<DirectObjUnknown>> {
+        self.cells
+            .lock()
+            .iter()
+            .filter(|cell| cell.strength == Strength::Soft && Arc::strong_count(cell) > 1)
+            .filter_map(|cell| cell.slot())
+            .collect()
+    }
+
+    /// Pick the unmarked objects held by soft references which should be kept alive. An object
+    /// survives if it has been used within the last `max_idle` milliseconds, so objects are kept
+    /// for longer while the heap has more room.
+    pub(crate) fn retained_soft_slots<F>(
+        &self,
+        is_dead: F,
+        max_idle: u64,
+    ) -> Vec<NonNull<DirectObjUnknown>>
+    where
+        F: Fn(NonNull<DirectObjUnknown>) -> bool,
+    {
+        let now = now_millis();
+
+        self.cells
+            .lock()
+            .iter()
+            .filter(|cell| cell.strength == Strength::Soft && Arc::strong_count(cell) > 1)
+            .filter(|cell| cell.idle_millis(now) <= max_idle)
+            .filter_map(|cell| cell.slot())
+            .filter(|slot| is_dead(*slot))
+            .collect()
+    }
+
+    /// Clear every reference to one of the given slots, along with every ephemeron whose key or
+    /// value was in one of them. Cleared references are pushed onto their queues. Cells which are
+    /// no longer held by any reference or ephemeron are forgotten.
+    ///
+    /// # Safety
+    /// All threads must be stopped, and `dead` must only hold slots whose objects have died.
+    pub(crate) unsafe fn clear(&self, dead: &HashSet<NonNull<DirectObjUnknown>>) {
+        let mut cells = self.cells.lock();
+
+        // A cell only referenced by this list can never be upgraded or enqueued
+        cells.retain(|cell| Arc::strong_count(cell) > 1);
+
+        for cell in cells.iter() {
+            if let Some(slot) = cell.slot() {
+                if dead.contains(&slot) {
+                    cell.slot.store(null_mut(), Ordering::SeqCst);
+
+                    if let Some(queue) = &cell.queue {
+                        queue.push(cell.clone());
+                    }
+                }
+            }
+        }
+
+        // Cleared cells are only kept alive by their references and queues
+        cells.retain(|cell| cell.slot().is_some());
+
+        let mut ephemerons = self.ephemerons.lock();
+        ephemerons.retain(|cell| Arc::strong_count(cell) > 1);
+
+        for cell in ephemerons.iter() {
+            if let Some((key, value)) = cell.get() {
+                // A value which is not traced through its ephemeron may die before its key
+                if dead.contains(&key) || dead.contains(&value) {
+                    cell.key.store(null_mut(), Ordering::SeqCst);
+                    cell.value.store(null_mut(), Ordering::SeqCst);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn weak_pointers_are_cleared_once_unreachable() {
+    use crate::alloc::VirtualMachine;
+    use crate::mem::nursery::TenuringPolicy;
+
+    let vm = VirtualMachine::<u64>::test_builder()
+        .tenuring(TenuringPolicy::Fixed(0))
+        .build();
+    let allocator = vm.attach_thread();
+
+    let live = allocator.allocate(1).unwrap();
+    let live = WeakGcPtr::new(&allocator, live);
+
+    let old = {
+        let scope = allocator.handle_scope();
+        let old = WeakGcPtr::new(&allocator, scope.allocate(3).unwrap());
+
+        // Rooted objects stay alive even when they are otherwise only weakly referenced
+        vm.collect_minor();
+        assert!(!old.is_cleared());
+        old
+    };
+
+    // Upgrading roots the object again until the end of the upgrading scope
+    {
+        let scope = allocator.handle_scope();
+        let root = old.upgrade(&scope).unwrap();
+        vm.collect_major(false);
+        assert_eq!(unsafe { *root.direct_ptr() }, 3);
+    }
+
+    let young = {
+        let scope = allocator.handle_scope();
+        WeakGcPtr::new(&allocator, scope.allocate(2).unwrap())
+    };
+
+    vm.collect_minor();
+    assert!(young.is_cleared());
+    assert!(young.clone().is_cleared());
+
+    // Only a major collection can prove an object in the old generation is dead
+    assert!(!old.is_cleared());
+    vm.collect_major(false);
+    assert!(old.is_cleared());
+
+    // Releasing the root of an upgrade leaves objects which were already rooted as roots
+    {
+        let scope = allocator.handle_scope();
+        let root = live.upgrade(&scope).unwrap();
+        assert_eq!(unsafe { *root.direct_ptr() }, 1);
+    }
+    vm.collect_major(false);
+    assert!(!live.is_cleared());
+
+    drop(young);
+    drop(old);
+    vm.collect_minor();
+    assert_eq!(vm.weak_refs().cells.lock().len(), 1);
+}
+
+#[test]
+#[cfg(test)]
+fn ephemerons_keep_values_alive_only_through_live_keys() {
+    use crate::alloc::VirtualMachine;
+    use crate::ptr::GcCell;
+
+    enum Object {
+        Leaf(u64),
+        Ref(GcCell<Object>),
+        Table(GcWeakKeyMap<Object, Object>),
+    }
+
+    impl Trace for Object {
+        unsafe fn trace(&self, cxt: &mut TraceContext) {
+            match self {
+                Object::Leaf(_) => {}
+                Object::Ref(cell) => cell.trace(cxt),
+                Object::Table(map) => map.trace(cxt),
+            }
+        }
+    }
+
+    let vm = VirtualMachine::<Object>::test_builder().build();
+    let allocator = vm.attach_thread();
+    let table = allocator
+        .allocate(Object::Table(GcWeakKeyMap::new()))
+        .unwrap();
+    let map = || match unsafe { &mut *table.direct_ptr() } {
+        Object::Table(map) => map,
+        _ => unreachable!(),
+    };
+    let leaf = |root: Root<'_, Object>| match unsafe { &*root.direct_ptr() } {
+        Object::Leaf(x) => *x,
+        _ => unreachable!(),
+    };
+
+    let live = allocator.allocate(Object::Leaf(1)).unwrap();
+    let (cyclic, chained) = {
+        let scope = allocator.handle_scope();
+
+        // A value which refers back to its key must not keep the key alive
+        let cyclic = scope.allocate(Object::Leaf(2)).unwrap();
+        let value = scope.allocate(Object::Ref(GcCell::new(cyclic))).unwrap();
+        map().insert(&allocator, cyclic, value);
+
+        // Only reachable through the value of the live key, so it takes a second round of
+        // marking to find the value of this key
+        let chained = scope.allocate(Object::Leaf(3)).unwrap();
+        let value = scope.allocate(Object::Leaf(4)).unwrap();
+        map().insert(&allocator, chained, value);
+        let value = scope.allocate(Object::Ref(GcCell::new(chained))).unwrap();
+        map().insert(&allocator, live, value);
+
+        (
+            WeakGcPtr::new(&allocator, cyclic),
+            WeakGcPtr::new(&allocator, chained),
+        )
+    };
+
+    // Minor collections treat ephemeron values as strong
+    vm.collect_minor();
+    assert!(!cyclic.is_cleared());
+
+    vm.collect_major(false);
+    assert!(cyclic.is_cleared());
+    let scope = allocator.handle_scope();
+    let chained = chained.upgrade(&scope).unwrap();
+    assert_eq!(leaf(map().get(&chained, &scope).unwrap()), 4);
+    assert!(map().get(&live, &scope).is_some());
+
+    assert_eq!(map().len(), 3);
+    map().remove_cleared();
+    assert_eq!(map().len(), 2);
+    assert_eq!(map().iter(&scope).count(), 2);
+}
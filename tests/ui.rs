@@ -0,0 +1,4 @@
+#[test]
+fn derived_trace_rejects_untraced_fields() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
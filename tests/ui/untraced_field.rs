@@ -0,0 +1,11 @@
+use generational_gc::ptr::GcPtr;
+use generational_gc_derive::Trace;
+
+struct Hidden(GcPtr<u64>);
+
+#[derive(Trace)]
+struct Holder {
+    hidden: Hidden,
+}
+
+fn main() {}